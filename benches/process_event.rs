@@ -0,0 +1,112 @@
+//! Throughput and latency of the events going through the filter, the thread pool and the handler of a watch
+//!
+//! Run with `cargo bench --features testing`.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use crossbeam_channel::unbounded;
+use notify::event::{CreateKind, ModifyKind};
+use notify::{Event, EventKind};
+use watchcrab::handler::HandlerError;
+use watchcrab::testing::Harness;
+use watchcrab::watch::Watch;
+
+/// Events per iteration of the throughput benchmark
+const BATCH: usize = 1000;
+
+fn events() -> Vec<Event> {
+    (0..BATCH)
+        .map(|i| {
+            let kind = if i % 4 == 0 {
+                EventKind::Create(CreateKind::File)
+            } else {
+                EventKind::Modify(ModifyKind::Any)
+            };
+            Event::new(kind).add_path(format!("/project/src/file_{}.rs", i % 64).into())
+        })
+        .collect()
+}
+
+/// Events handled per second, from the first event injected to the last one handled
+///
+/// The watch and its thread pool are built once per benchmark, only the injection and the handling are timed.
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    let filters = [vec!["all".to_string()], vec!["create".to_string()]];
+    for threads in [1, 4] {
+        for filter in &filters {
+            let id = BenchmarkId::new(format!("threads_{}", threads), &filter[0]);
+            group.bench_with_input(id, filter, |b, filter| {
+                let events = events();
+                let accepted = events
+                    .iter()
+                    .filter(|event| filter[0] == "all" || event.kind.is_create())
+                    .count();
+                let (tx, rx) = unbounded();
+                let f = move |_: Event| -> Result<(), HandlerError> {
+                    let _ = tx.send(());
+                    Ok(())
+                };
+                let harness = Harness::new(Watch::new_fallible(
+                    Path::new("/project"),
+                    true,
+                    filter,
+                    f,
+                    threads,
+                ));
+                b.iter(|| {
+                    for event in &events {
+                        harness.inject(event.clone());
+                    }
+                    for _ in 0..accepted {
+                        rx.recv().unwrap();
+                    }
+                });
+                harness.finish().unwrap();
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Time between an event being injected and its handler being called
+fn latency(c: &mut Criterion) {
+    let mut group = c.benchmark_group("latency");
+    let filter = vec!["all".to_string()];
+    for threads in [1, 4] {
+        group.bench_function(format!("threads_{}", threads), |b| {
+            let (tx, rx) = unbounded();
+            let f = move |_: Event| -> Result<(), HandlerError> {
+                let _ = tx.send(Instant::now());
+                Ok(())
+            };
+            let harness = Harness::new(Watch::new_fallible(
+                Path::new("/project"),
+                true,
+                &filter,
+                f,
+                threads,
+            ));
+            let event =
+                Event::new(EventKind::Modify(ModifyKind::Any)).add_path("/project/a.rs".into());
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    harness.inject(event.clone());
+                    let handled = rx.recv().unwrap();
+                    total += handled.duration_since(start);
+                }
+                total
+            });
+            harness.finish().unwrap();
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput, latency);
+criterion_main!(benches);
@@ -0,0 +1,202 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::queue::QueueSender;
+#[cfg(feature = "testing")]
+use crate::testing::{FakeBackend, FakeWatcher};
+use notify::{
+    Config, Event, EventHandler, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use tracing::warn;
+
+/// Default interval between two scans of the polling backend
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Filesystem notification backend used to watch a root
+///
+/// * `Native` - Uses the platform notification API (inotify, FSEvents, ReadDirectoryChangesW...)
+/// * `Poll` - Periodically scans the tree, works on NFS/SMB mounts and bind mounts inside containers
+/// * `Auto` - Uses the native backend and falls back to polling if the native watch can't be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    Native,
+    Poll,
+    Auto,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(Backend::Native),
+            "poll" => Ok(Backend::Poll),
+            "auto" => Ok(Backend::Auto),
+            _ => Err(format!(
+                "Invalid backend '{}', expected one of: native, poll, auto",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Native => write!(f, "native"),
+            Backend::Poll => write!(f, "poll"),
+            Backend::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Options of the polling backend
+///
+/// * `interval` - Time between two scans of the watched tree
+/// * `compare_contents` - Hash file contents to detect changes that keep the same modification time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollOptions {
+    pub interval: Duration,
+    pub compare_contents: bool,
+}
+
+impl Default for PollOptions {
+    fn default() -> Self {
+        PollOptions {
+            interval: DEFAULT_POLL_INTERVAL,
+            compare_contents: false,
+        }
+    }
+}
+
+/// Event handler of the watcher of a root, forwards the events to the watch channel
+///
+/// Rescan requests (e.g. on inotify queue overflow) carry no path, the root is attached to them so the affected tree is known.
+#[derive(Clone)]
+struct RootHandler {
+    root: PathBuf,
+    tx: QueueSender,
+}
+
+impl EventHandler for RootHandler {
+    fn handle_event(&mut self, event: Result<Event, notify::Error>) {
+        let event = event.map(|event| {
+            if event.need_rescan() && event.paths.is_empty() {
+                event.add_path(self.root.clone())
+            } else {
+                event
+            }
+        });
+        self.tx.send(event);
+    }
+}
+
+/// Create a watcher with the given backend and register `root` on it
+///
+/// With `Backend::Auto`, a failure to create the native watcher or to register the watch falls back to the polling backend.
+///
+/// # Errors
+/// Returns a `notify::Error` if the watcher can't be created or the watch can't be registered
+pub(crate) fn open_watcher(
+    root: &Path,
+    recursive_mode: RecursiveMode,
+    backend: Backend,
+    poll: PollOptions,
+    tx: QueueSender,
+) -> Result<Box<dyn Watcher + Send>, notify::Error> {
+    let handler = RootHandler {
+        root: root.to_path_buf(),
+        tx,
+    };
+    open_watcher_with(root, recursive_mode, backend, poll, handler)
+}
+
+/// Create a watcher attached to a fake backend and register `root` on it, see `testing::FakeBackend`
+#[cfg(feature = "testing")]
+pub(crate) fn open_fake_watcher(
+    root: &Path,
+    recursive_mode: RecursiveMode,
+    fake_backend: &FakeBackend,
+    tx: QueueSender,
+) -> Result<Box<dyn Watcher + Send>, notify::Error> {
+    let handler = RootHandler {
+        root: root.to_path_buf(),
+        tx,
+    };
+    let mut watcher = FakeWatcher::new(handler, Config::default())?;
+    watcher.watch(root, recursive_mode)?;
+    fake_backend.attach(&watcher);
+    Ok(Box::new(watcher))
+}
+
+fn open_watcher_with(
+    root: &Path,
+    recursive_mode: RecursiveMode,
+    backend: Backend,
+    poll: PollOptions,
+    handler: RootHandler,
+) -> Result<Box<dyn Watcher + Send>, notify::Error> {
+    match backend {
+        Backend::Native => {
+            let mut watcher = RecommendedWatcher::new(handler, Config::default())?;
+            watcher.watch(root, recursive_mode)?;
+            Ok(Box::new(watcher))
+        }
+        Backend::Poll => {
+            let config = Config::default()
+                .with_poll_interval(poll.interval)
+                .with_compare_contents(poll.compare_contents);
+            let mut watcher = PollWatcher::new(handler, config)?;
+            watcher.watch(root, recursive_mode)?;
+            Ok(Box::new(watcher))
+        }
+        Backend::Auto => {
+            match open_watcher_with(root, recursive_mode, Backend::Native, poll, handler.clone()) {
+                Ok(watcher) => Ok(watcher),
+                Err(e) => {
+                    warn!(root = %root.display(), error = %e, "Native watcher unavailable, falling back to polling");
+                    open_watcher_with(root, recursive_mode, Backend::Poll, poll, handler)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{event_queue, OverflowPolicy};
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_backend_from_str() {
+        assert_eq!("native".parse::<Backend>(), Ok(Backend::Native));
+        assert_eq!("poll".parse::<Backend>(), Ok(Backend::Poll));
+        assert_eq!("auto".parse::<Backend>(), Ok(Backend::Auto));
+        assert!("inotify".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn test_poll_watcher_detects_new_file() {
+        let dir = TempDir::new("poll");
+
+        let (tx, rx) = event_queue(0, OverflowPolicy::Block);
+        let poll = PollOptions {
+            interval: Duration::from_millis(50),
+            compare_contents: false,
+        };
+        let _watcher =
+            open_watcher(&dir, RecursiveMode::NonRecursive, Backend::Poll, poll, tx).unwrap();
+
+        fs::write(dir.join("new.txt"), "content").unwrap();
+        let event = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("no event received")
+            .unwrap();
+        assert!(event.kind.is_create());
+    }
+}
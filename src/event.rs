@@ -0,0 +1,82 @@
+use std::path::{Path, PathBuf};
+
+use notify::Event;
+use serde::{Deserialize, Serialize};
+
+/// Normalized kind of an event, as used in the events filter
+///
+/// Returns "access", "create", "modify", "remove", "rescan" (the backend missed events, see `Watch::rescan_on_overflow`) or "other".
+pub fn kind_name(event: &Event) -> &'static str {
+    if event.need_rescan() {
+        "rescan"
+    } else if event.kind.is_access() {
+        "access"
+    } else if event.kind.is_create() {
+        "create"
+    } else if event.kind.is_modify() {
+        "modify"
+    } else if event.kind.is_remove() {
+        "remove"
+    } else {
+        "other"
+    }
+}
+
+/// Whether an event passes the events filter
+///
+/// A filter of `["all"]` accepts every event, otherwise the normalized kind of the event must be in the filter.
+pub fn matches_filter(event: &Event, events_filter: &[String]) -> bool {
+    events_filter == ["all"] || events_filter.iter().any(|kind| kind == kind_name(event))
+}
+
+/// A filesystem event that passed the events filter
+///
+/// * `kind` - Normalized kind of the event, see `kind_name`
+/// * `event` - Event as sent by the backend, or synthesized by the initial scan, the state file or a rescan
+#[derive(Debug, Clone)]
+pub struct WatchEvent {
+    pub kind: &'static str,
+    pub event: Event,
+}
+
+impl WatchEvent {
+    pub fn new(event: Event) -> WatchEvent {
+        WatchEvent {
+            kind: kind_name(&event),
+            event,
+        }
+    }
+
+    /// Path of the file that triggered the event
+    pub fn path(&self) -> Option<&Path> {
+        self.event.paths.first().map(|path| path.as_path())
+    }
+}
+
+/// Serializable form of an event, as streamed by `server::Server`
+///
+/// * `kind` - Normalized kind of the event, see `kind_name`
+/// * `detail` - Kind of the event as reported by the backend, like `Create(File)`
+/// * `paths` - Paths affected by the event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventRecord {
+    pub kind: String,
+    pub detail: String,
+    pub paths: Vec<PathBuf>,
+}
+
+impl EventRecord {
+    pub fn new(event: &Event) -> EventRecord {
+        EventRecord {
+            kind: kind_name(event).to_string(),
+            detail: format!("{:?}", event.kind),
+            paths: event.paths.clone(),
+        }
+    }
+}
+
+impl From<WatchEvent> for Event {
+    fn from(event: WatchEvent) -> Event {
+        event.event
+    }
+}
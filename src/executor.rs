@@ -0,0 +1,198 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use notify::Event;
+use threadpool::ThreadPool;
+use tracing::error;
+
+use crate::handler::panic_message;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Key used to serialize the handling of events in the thread pool
+///
+/// Events with the same key are handled one at a time in the order they were received, events with different keys run in parallel.
+///
+/// * `None` - Events are not serialized, two events of the same file can be handled concurrently
+/// * `Path` - Events of the same path are serialized
+/// * `Parent` - Events of paths in the same directory are serialized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SerializeBy {
+    #[default]
+    None,
+    Path,
+    Parent,
+}
+
+impl SerializeBy {
+    /// Key of an event, `None` if the event does not need to be serialized
+    pub fn key(&self, event: &Event) -> Option<PathBuf> {
+        let path = event.paths.first()?;
+        match self {
+            SerializeBy::None => None,
+            SerializeBy::Path => Some(path.clone()),
+            SerializeBy::Parent => Some(path.parent().unwrap_or(path).to_path_buf()),
+        }
+    }
+}
+
+impl FromStr for SerializeBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SerializeBy::None),
+            "path" => Ok(SerializeBy::Path),
+            "parent" => Ok(SerializeBy::Parent),
+            _ => Err(format!(
+                "Invalid serialization key '{}', expected one of: none, path, parent",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SerializeBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerializeBy::None => write!(f, "none"),
+            SerializeBy::Path => write!(f, "path"),
+            SerializeBy::Parent => write!(f, "parent"),
+        }
+    }
+}
+
+/// Thread pool that runs the jobs sharing a key strictly in order
+///
+/// The first job of a key is queued in the thread pool, the following ones wait in a per-key queue and are run by the same worker
+/// once the previous job of the key finished, so a busy key never holds more than one thread.
+pub struct KeyedExecutor {
+    pool: ThreadPool,
+    serialize_by: SerializeBy,
+    pending: Arc<Mutex<HashMap<PathBuf, VecDeque<Job>>>>,
+}
+
+impl KeyedExecutor {
+    pub fn new(num_threads: usize, serialize_by: SerializeBy) -> KeyedExecutor {
+        KeyedExecutor {
+            pool: ThreadPool::new(num_threads),
+            serialize_by,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Set the key used to serialize the events
+    pub fn set_serialize_by(&mut self, serialize_by: SerializeBy) {
+        self.serialize_by = serialize_by;
+    }
+
+    /// Key used to serialize the events
+    pub fn serialize_by(&self) -> SerializeBy {
+        self.serialize_by
+    }
+
+    /// Run a job handling an event, after every previous job of the same key finished
+    pub fn execute<F>(&self, event: &Event, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let Some(key) = self.serialize_by.key(event) else {
+            self.pool.execute(job);
+            return;
+        };
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(queue) = pending.get_mut(&key) {
+                // A job of the same key is running, it will run this one when it finishes
+                queue.push_back(Box::new(job));
+                return;
+            }
+            pending.insert(key.clone(), VecDeque::new());
+        }
+
+        let pending = Arc::clone(&self.pending);
+        self.pool.execute(move || {
+            let mut job: Job = Box::new(job);
+            loop {
+                // A panicking job must not leave the next jobs of its key waiting forever
+                if let Err(payload) = catch_unwind(AssertUnwindSafe(job)) {
+                    error!(key = %key.display(), panic = %panic_message(payload), "Job panicked");
+                }
+                let mut pending = pending.lock().unwrap();
+                match pending.get_mut(&key).and_then(|queue| queue.pop_front()) {
+                    Some(next) => job = next,
+                    None => {
+                        pending.remove(&key);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Wait for every job, including the ones waiting for their key, to finish
+    pub fn join(&self) {
+        self.pool.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::EventKind;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_same_path_runs_in_order() {
+        let executor = KeyedExecutor::new(4, SerializeBy::Path);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..5u64 {
+            let order = Arc::clone(&order);
+            let event = Event::new(EventKind::Any).add_path(PathBuf::from("/same"));
+            executor.execute(&event, move || {
+                // Earlier jobs sleep longer, so they would finish last if run concurrently
+                thread::sleep(Duration::from_millis(50 - i * 10));
+                order.lock().unwrap().push(i);
+            });
+        }
+        executor.join();
+
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_panicking_job_runs_the_next_jobs_of_its_key() {
+        let executor = KeyedExecutor::new(2, SerializeBy::Path);
+        let event = Event::new(EventKind::Any).add_path(PathBuf::from("/same"));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        executor.execute(&event, || panic!("job failed"));
+        for i in 0..2 {
+            let order = Arc::clone(&order);
+            executor.execute(&event, move || order.lock().unwrap().push(i));
+        }
+        executor.join();
+        assert_eq!(*order.lock().unwrap(), vec![0, 1]);
+
+        // The key was released, a new job runs right away
+        let after = Arc::clone(&order);
+        executor.execute(&event, move || after.lock().unwrap().push(2));
+        executor.join();
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+        assert!(executor.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parent_key() {
+        let event = Event::new(EventKind::Any).add_path(PathBuf::from("/dir/file"));
+        assert_eq!(SerializeBy::Parent.key(&event), Some(PathBuf::from("/dir")));
+        assert_eq!(SerializeBy::None.key(&event), None);
+    }
+}
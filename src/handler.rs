@@ -0,0 +1,548 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_channel::Sender;
+use notify::Event;
+use tracing::error;
+
+/// Error returned by a fallible handler
+pub type HandlerError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Handler of the events, every handler passed to a `Watch` is turned into a fallible one
+pub type Handler = Arc<dyn Fn(Event) -> Result<(), HandlerError> + Send + Sync + 'static>;
+
+/// Function called when a handler fails, with the event it was handling
+pub type ErrorCallback = Arc<dyn Fn(&Event, &HandlerFailure) + Send + Sync + 'static>;
+
+/// Function called with the events that were not passed to the handler, and why
+pub type SkipCallback = Arc<dyn Fn(&Event, SkipReason) + Send + Sync + 'static>;
+
+/// Why an event was not passed to the handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Its kind is not in the events filter of the watch
+    Kind,
+    /// The predicate of a `layer::Filter` rejected it
+    Filter,
+    /// A later event of the same paths replaced it in `layer::Debounce`
+    Debounce,
+    /// `layer::Throttle` dropped it
+    Throttle,
+    /// `layer::RateLimit` dropped it
+    RateLimit,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Kind => write!(f, "kind filter"),
+            SkipReason::Filter => write!(f, "filter"),
+            SkipReason::Debounce => write!(f, "debounce"),
+            SkipReason::Throttle => write!(f, "throttle"),
+            SkipReason::RateLimit => write!(f, "rate limit"),
+        }
+    }
+}
+
+/// Function called at each step of the handling of an event, see `Decision`
+pub type TraceCallback = Arc<dyn Fn(&Event, &Decision) + Send + Sync + 'static>;
+
+/// Step of the handling of an event, from its reception to the call of the handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// The watch received the event from the backend, the initial scan, the state file or a rescan
+    Received,
+    /// The events filter accepted it, with the entry of the filter it matched: "all" or its normalized kind
+    Accepted(&'static str),
+    /// The event will not be handled
+    Skipped(SkipReason),
+    /// Passed to the layers and the handler, see `Target`
+    Dispatched(Target),
+    /// `layer::Debounce` holds it until no other event of its paths was received for the delay
+    Debounced(Duration),
+    /// `layer::Debounce` passes it on, no other event of its paths was received for the delay
+    Released,
+    /// `layer::Throttle` delays it by this duration, with `ThrottlePolicy::Queue`
+    Throttled(Duration),
+    /// `layer::RateLimit` delays it by this duration, with `ThrottlePolicy::Queue`
+    RateLimited(Duration),
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decision::Received => write!(f, "received"),
+            Decision::Accepted(filter) => write!(f, "accepted by the events filter ({})", filter),
+            Decision::Skipped(reason) => write!(f, "skipped by {}", reason),
+            Decision::Dispatched(target) => write!(f, "dispatched to {}", target),
+            Decision::Debounced(delay) => write!(f, "held by debounce for {:?}", delay),
+            Decision::Released => write!(f, "released by debounce"),
+            Decision::Throttled(delay) => write!(f, "delayed by throttle for {:?}", delay),
+            Decision::RateLimited(delay) => write!(f, "delayed by rate limit for {:?}", delay),
+        }
+    }
+}
+
+/// Where a watch passes an event to its layers and handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The thread receiving the events, without thread pool
+    Inline,
+    /// The thread pool, after the previous events of `key` when the events are serialized, see `executor::SerializeBy`
+    Pool { key: Option<PathBuf> },
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Inline => write!(f, "the handler"),
+            Target::Pool { key: None } => write!(f, "the thread pool"),
+            Target::Pool { key: Some(key) } => {
+                write!(f, "the thread pool, serialized by {}", key.display())
+            }
+        }
+    }
+}
+
+/// Callbacks of a watch or a layer reporting what it does with the events
+#[derive(Clone, Default)]
+pub(crate) struct Reporter {
+    pub(crate) on_skipped: Option<SkipCallback>,
+    pub(crate) on_trace: Option<TraceCallback>,
+}
+
+impl Reporter {
+    /// Whether the decisions are traced, to avoid building the ones that are costly for nothing
+    pub(crate) fn tracing(&self) -> bool {
+        self.on_trace.is_some()
+    }
+
+    /// Report a decision taken on an event
+    pub(crate) fn report(&self, event: &Event, decision: Decision) {
+        if let (Some(on_skipped), Decision::Skipped(reason)) = (&self.on_skipped, &decision) {
+            on_skipped(event, *reason);
+        }
+        if let Some(on_trace) = &self.on_trace {
+            on_trace(event, &decision);
+        }
+    }
+}
+
+/// Why a handler failed to handle an event
+#[derive(Debug)]
+pub enum HandlerFailure {
+    /// The handler returned an error
+    Error(HandlerError),
+    /// The handler panicked, with the panic message
+    Panic(String),
+}
+
+impl fmt::Display for HandlerFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerFailure::Error(e) => write!(f, "{}", e),
+            HandlerFailure::Panic(message) => write!(f, "handler panicked: {}", message),
+        }
+    }
+}
+
+/// Counters of the events handled since the watcher started
+///
+/// Only the events reaching the handler are counted, not the ones a layer skipped.
+#[derive(Debug, Default)]
+pub struct HandlerStats {
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    panicked: AtomicU64,
+    consecutive_failures: AtomicU64,
+}
+
+impl HandlerStats {
+    /// Number of events handled without error
+    pub fn succeeded(&self) -> u64 {
+        self.succeeded.load(Ordering::Relaxed)
+    }
+
+    /// Number of events whose handler returned an error or panicked
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Number of events whose handler panicked, included in `failed`
+    pub fn panicked(&self) -> u64 {
+        self.panicked.load(Ordering::Relaxed)
+    }
+
+    /// Number of failures since the last success
+    pub fn consecutive_failures(&self) -> u64 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+}
+
+/// Default error callback, logs the failure as an error
+pub fn print_failure(event: &Event, failure: &HandlerFailure) {
+    match event.paths.first() {
+        Some(path) => error!(path = %path.display(), %failure, "Handler failed"),
+        None => error!(%failure, "Handler failed"),
+    }
+}
+
+/// Calls the handler, catching its errors and panics to report them and count them
+///
+/// The failures are counted where the handler runs, inside the layers, so the events released later by a layer
+/// (e.g. `layer::Debounce`) are counted too, and the events a layer holds or skips are not counted as handled.
+pub(crate) struct Dispatcher {
+    handler: Handler,
+    accounting: Arc<Accounting>,
+    layered: bool,
+}
+
+impl Dispatcher {
+    /// # Arguments
+    /// * `max_consecutive_failures` - Number of consecutive failures after which `failure_tx` receives a message, `None` to never stop
+    pub fn new(
+        handler: Handler,
+        on_error: ErrorCallback,
+        stats: Arc<HandlerStats>,
+        max_consecutive_failures: Option<u64>,
+        failure_tx: Sender<()>,
+    ) -> Dispatcher {
+        let accounting = Arc::new(Accounting {
+            on_error,
+            stats,
+            max_consecutive_failures,
+            failure_tx: Some(failure_tx),
+            stopped: AtomicBool::new(false),
+        });
+        Dispatcher {
+            handler: accounted(handler, Arc::clone(&accounting)),
+            accounting,
+            layered: false,
+        }
+    }
+
+    /// Wrap the handler in layers, the failures of the handler are still counted when a layer calls it later
+    pub fn wrap<W>(mut self, wrap: W) -> Dispatcher
+    where
+        W: FnOnce(Handler) -> Handler,
+    {
+        self.handler = wrap(self.handler);
+        self.layered = true;
+        self
+    }
+
+    /// Whether the maximum number of consecutive failures was reached
+    pub fn stopped(&self) -> bool {
+        self.accounting.stopped()
+    }
+
+    /// Failure counters shared with the handler, to check the failure limit once the layers are dropped
+    pub fn accounting(&self) -> Arc<Accounting> {
+        Arc::clone(&self.accounting)
+    }
+
+    /// Handle an event, a panic of the handler is caught so it never kills a worker thread
+    pub fn call(&self, event: Event) {
+        // Without layers, the handler reports all its failures itself
+        if !self.layered {
+            let _ = (self.handler)(event);
+            return;
+        }
+        let failed_event = event.clone();
+        let failure = match catch_unwind(AssertUnwindSafe(|| (self.handler)(event))) {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) if is_reported(&e) => return,
+            Ok(Err(e)) => HandlerFailure::Error(e),
+            Err(payload) => HandlerFailure::Panic(panic_message(payload)),
+        };
+        // The layer itself failed
+        self.accounting.record(&failed_event, Err(&failure));
+    }
+}
+
+/// Counters and callbacks of the failures of a handler, shared by a dispatcher and the handler it wraps
+///
+/// Also runs the handlers called outside of a `Watch`, e.g. for the events received from a server, the same way.
+pub struct Accounting {
+    on_error: ErrorCallback,
+    stats: Arc<HandlerStats>,
+    max_consecutive_failures: Option<u64>,
+    // Kept without limit too, the watch stops when it is dropped
+    failure_tx: Option<Sender<()>>,
+    stopped: AtomicBool,
+}
+
+impl Accounting {
+    /// Accounting without failure limit
+    pub fn new(on_error: ErrorCallback, stats: Arc<HandlerStats>) -> Accounting {
+        Accounting {
+            on_error,
+            stats,
+            max_consecutive_failures: None,
+            failure_tx: None,
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the maximum number of consecutive failures was reached
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Run a handler for an event, catching its error or panic to count it and pass it to the error callback
+    ///
+    /// The handler is not run once the maximum number of consecutive failures was reached.
+    ///
+    /// # Errors
+    /// Returns the failure of the handler, already reported
+    pub fn run<F>(&self, event: &Event, handler: F) -> Result<(), HandlerFailure>
+    where
+        F: FnOnce() -> Result<(), HandlerError>,
+    {
+        if self.stopped() {
+            return Ok(());
+        }
+        let failure = match catch_unwind(AssertUnwindSafe(handler)) {
+            Ok(Ok(())) => {
+                self.record(event, Ok(()));
+                return Ok(());
+            }
+            Ok(Err(e)) => HandlerFailure::Error(e),
+            Err(payload) => HandlerFailure::Panic(panic_message(payload)),
+        };
+        self.record(event, Err(&failure));
+        Err(failure)
+    }
+
+    fn record(&self, event: &Event, result: Result<(), &HandlerFailure>) {
+        match result {
+            Ok(()) => {
+                self.stats.succeeded.fetch_add(1, Ordering::Relaxed);
+                self.stats.consecutive_failures.store(0, Ordering::Relaxed);
+            }
+            Err(failure) => {
+                if let HandlerFailure::Panic(_) = failure {
+                    self.stats.panicked.fetch_add(1, Ordering::Relaxed);
+                }
+                self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                let consecutive = self
+                    .stats
+                    .consecutive_failures
+                    .fetch_add(1, Ordering::Relaxed)
+                    + 1;
+                (self.on_error)(event, failure);
+
+                if let (Some(max), Some(failure_tx)) =
+                    (self.max_consecutive_failures, &self.failure_tx)
+                {
+                    if consecutive >= max && !self.stopped.swap(true, Ordering::SeqCst) {
+                        let _ = failure_tx.try_send(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Failure already counted and passed to the error callback, returned to the layers wrapping the handler
+#[derive(Debug)]
+struct Reported(HandlerFailure);
+
+impl fmt::Display for Reported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for Reported {}
+
+/// Whether a handler error was already reported by the dispatcher, see `Dispatcher::wrap`
+pub(crate) fn is_reported(e: &HandlerError) -> bool {
+    e.is::<Reported>()
+}
+
+/// Count the results of a handler, the events are no longer handled once the failure limit is reached
+fn accounted(handler: Handler, accounting: Arc<Accounting>) -> Handler {
+    Arc::new(move |event: Event| {
+        // Pending events, e.g. released by a layer, are not handled once the handler failed too many times
+        if accounting.stopped() {
+            return Ok(());
+        }
+        let failed_event = event.clone();
+        accounting
+            .run(&failed_event, || handler(event))
+            .map_err(|failure| Box::new(Reported(failure)) as HandlerError)
+    })
+}
+
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic payload")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::Debounce;
+    use crate::watch::Watch;
+    use crossbeam_channel::bounded;
+    use notify::event::{CreateKind, ModifyKind};
+    use notify::EventKind;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_accounting_runs_handlers_outside_of_a_watch() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let on_error: ErrorCallback = {
+            let reported = Arc::clone(&reported);
+            Arc::new(move |_: &Event, failure: &HandlerFailure| {
+                reported.lock().unwrap().push(failure.to_string())
+            })
+        };
+        let stats = Arc::new(HandlerStats::default());
+        let accounting = Accounting::new(on_error, Arc::clone(&stats));
+        let event = Event::new(EventKind::Any).add_path("/a".into());
+
+        assert!(accounting.run(&event, || panic!("spawn failed")).is_err());
+        assert!(accounting.run(&event, || Err("exit 1".into())).is_err());
+        assert!(accounting.run(&event, || Ok(())).is_ok());
+
+        assert!(!accounting.stopped());
+        assert_eq!(stats.failed(), 2);
+        assert_eq!(stats.panicked(), 1);
+        assert_eq!(stats.succeeded(), 1);
+        assert_eq!(stats.consecutive_failures(), 0);
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec!["handler panicked: spawn failed", "exit 1"]
+        );
+    }
+
+    #[test]
+    fn test_failures_are_counted_and_stop_the_watcher() {
+        let handler: Handler = Arc::new(|event: Event| {
+            if event.paths.is_empty() {
+                panic!("no path");
+            }
+            Err("always fails".into())
+        });
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let on_error: ErrorCallback = {
+            let reported = Arc::clone(&reported);
+            Arc::new(move |_: &Event, failure: &HandlerFailure| {
+                reported.lock().unwrap().push(failure.to_string())
+            })
+        };
+        let stats = Arc::new(HandlerStats::default());
+        let (failure_tx, failure_rx) = bounded(1);
+        let dispatcher =
+            Dispatcher::new(handler, on_error, Arc::clone(&stats), Some(2), failure_tx);
+
+        dispatcher.call(Event::new(EventKind::Any).add_path("/a".into()));
+        assert!(!dispatcher.stopped());
+        dispatcher.call(Event::new(EventKind::Any));
+
+        assert!(dispatcher.stopped());
+        assert!(failure_rx.try_recv().is_ok());
+        assert_eq!(stats.failed(), 2);
+        assert_eq!(stats.panicked(), 1);
+        assert_eq!(stats.consecutive_failures(), 2);
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec!["always fails", "handler panicked: no path"]
+        );
+    }
+
+    #[test]
+    fn test_failures_released_by_debounce_stop_the_watch() {
+        let events = vec!["create".to_string()];
+        let create =
+            |path: &str| Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(path.into()));
+        let f = |_: Event| -> Result<(), String> { Err("always fails".to_string()) };
+        let failures = Arc::new(Mutex::new(0));
+        let on_error = {
+            let failures = Arc::clone(&failures);
+            move |_: &Event, _: &HandlerFailure| *failures.lock().unwrap() += 1
+        };
+        let watch = Watch::new_fallible(Path::new("/"), true, &events, f, 1)
+            .on_error(on_error)
+            .max_consecutive_failures(3)
+            .layer(Debounce::new(Duration::from_secs(60)));
+
+        // The debounced events are released when the replay ends, the ones after the third failure are not handled
+        let result = watch.replay(["/a", "/b", "/c", "/d"].map(create));
+
+        assert!(result.is_err());
+        assert_eq!(*failures.lock().unwrap(), 3);
+        let stats = watch.handler_stats();
+        assert_eq!(stats.failed(), 3);
+        assert_eq!(stats.succeeded(), 0);
+    }
+
+    #[test]
+    fn test_decisions_trace_the_events_through_the_watch() {
+        let traced = Arc::new(Mutex::new(Vec::new()));
+        let on_trace = {
+            let traced = Arc::clone(&traced);
+            move |event: &Event, decision: &Decision| {
+                let path = event.paths[0].display();
+                traced
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", path, decision));
+            }
+        };
+        let skipped = Arc::new(Mutex::new(Vec::new()));
+        let on_skipped = {
+            let skipped = Arc::clone(&skipped);
+            move |_: &Event, reason: SkipReason| skipped.lock().unwrap().push(reason)
+        };
+
+        let create =
+            |path: &str| Event::new(EventKind::Create(CreateKind::File)).add_path(path.into());
+        let events = vec![
+            Ok(create("/a")),
+            Ok(Event::new(EventKind::Modify(ModifyKind::Any)).add_path("/a".into())),
+            Ok(create("/b")),
+        ];
+        let filter = vec!["create".to_string()];
+        let f = |_: Event| -> Result<(), String> { Ok(()) };
+        // The debounced events are released when the replay ends
+        Watch::new_fallible(Path::new("/"), true, &filter, f, 1)
+            .on_skipped(on_skipped)
+            .on_trace(on_trace.clone())
+            .layer(Debounce::new(Duration::from_secs(60)).on_trace(on_trace))
+            .replay(events)
+            .unwrap();
+
+        assert_eq!(
+            *traced.lock().unwrap(),
+            vec![
+                "/a received",
+                "/a accepted by the events filter (create)",
+                "/a dispatched to the handler",
+                "/a held by debounce for 60s",
+                "/a received",
+                "/a skipped by kind filter",
+                "/b received",
+                "/b accepted by the events filter (create)",
+                "/b dispatched to the handler",
+                "/b held by debounce for 60s",
+                "/a released by debounce",
+                "/b released by debounce",
+            ]
+        );
+        assert_eq!(*skipped.lock().unwrap(), vec![SkipReason::Kind]);
+    }
+}
@@ -0,0 +1,311 @@
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, params_from_iter, Connection};
+use tracing::{debug, error};
+
+use crate::event::EventRecord;
+use crate::router::PathGlob;
+
+/// Interval between two prunings of the entries older than the retention
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// An event and the result of the command it ran
+///
+/// * `time` - When the event was handled
+/// * `kind`, `detail`, `paths` - The event, see `event::EventRecord`
+/// * `command` - Command run for the event, `None` if no command was run
+/// * `exit_code` - Exit code of the command, `None` if it didn't run or was killed by a signal
+/// * `duration` - Time the command took
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub kind: String,
+    pub detail: String,
+    pub paths: Vec<PathBuf>,
+    pub command: Option<String>,
+    pub exit_code: Option<i32>,
+    pub duration: Option<Duration>,
+}
+
+impl Entry {
+    /// Entry of an event handled now, without a command
+    pub fn new(record: &EventRecord) -> Entry {
+        Entry {
+            time: SystemTime::now(),
+            kind: record.kind.clone(),
+            detail: record.detail.clone(),
+            paths: record.paths.clone(),
+            command: None,
+            exit_code: None,
+            duration: None,
+        }
+    }
+}
+
+/// Filter of the entries returned by `History::query`
+///
+/// * `since`, `until` - Time range of the entries, `until` excluded
+/// * `glob` - Glob pattern matched against the paths, see `router::PathGlob`, patterns containing `/` are matched against the absolute path
+/// * `kinds` - Normalized kinds of the entries, every kind if empty
+/// * `limit` - Maximum number of entries, the most recent ones are returned
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    pub glob: Option<String>,
+    pub kinds: Vec<String>,
+    pub limit: Option<usize>,
+}
+
+/// SQLite database recording the events and the results of their commands
+///
+/// The history can be shared by several threads, each entry is written in its own transaction.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// use std::time::{Duration, SystemTime};
+/// use watchcrab::history::{History, Query};
+///
+/// let history = History::open(Path::new("history.db")).unwrap();
+/// let query = Query {
+///     since: Some(SystemTime::now() - Duration::from_secs(12 * 60 * 60)),
+///     glob: Some(String::from("*.md")),
+///     ..Query::default()
+/// };
+/// for entry in history.query(&query).unwrap() {
+///     println!("{} {:?}", entry.kind, entry.paths);
+/// }
+/// ```
+pub struct History {
+    connection: Mutex<Connection>,
+    retain: Option<Duration>,
+    last_prune: Mutex<Option<Instant>>,
+}
+
+impl History {
+    /// Open the database, creating it if needed
+    ///
+    /// # Errors
+    /// Returns an `Error` if the database can't be opened or is not a history database
+    pub fn open(path: &Path) -> Result<History, Error> {
+        let connection = Connection::open(path).map_err(Error::other)?;
+        connection
+            .execute_batch(
+                "PRAGMA journal_mode = WAL;
+                 CREATE TABLE IF NOT EXISTS events (
+                     id INTEGER PRIMARY KEY,
+                     time INTEGER NOT NULL,
+                     kind TEXT NOT NULL,
+                     detail TEXT NOT NULL,
+                     paths TEXT NOT NULL,
+                     command TEXT,
+                     exit_code INTEGER,
+                     duration INTEGER
+                 );
+                 CREATE INDEX IF NOT EXISTS events_time ON events (time);",
+            )
+            .map_err(Error::other)?;
+        connection
+            .busy_timeout(Duration::from_secs(5))
+            .map_err(Error::other)?;
+        Ok(History {
+            connection: Mutex::new(connection),
+            retain: None,
+            last_prune: Mutex::new(None),
+        })
+    }
+
+    /// Delete the entries older than `age`, when recording the first entry and then every hour
+    pub fn retain(mut self, age: Duration) -> Self {
+        self.retain = Some(age);
+        self
+    }
+
+    /// Record an entry
+    ///
+    /// # Errors
+    /// Returns an `Error` if the entry can't be written
+    pub fn record(&self, entry: &Entry) -> Result<(), Error> {
+        let paths = serde_json::to_string(&entry.paths)?;
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO events (time, kind, detail, paths, command, exit_code, duration)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    to_micros(entry.time),
+                    entry.kind,
+                    entry.detail,
+                    paths,
+                    entry.command,
+                    entry.exit_code,
+                    entry.duration.map(|duration| duration.as_micros() as i64),
+                ],
+            )
+            .map_err(Error::other)?;
+
+        if let Some(age) = self.retain {
+            let mut last_prune = self.last_prune.lock().unwrap();
+            if last_prune.is_none_or(|last| last.elapsed() >= PRUNE_INTERVAL) {
+                *last_prune = Some(Instant::now());
+                match self.prune(SystemTime::now() - age) {
+                    Ok(pruned) => debug!(pruned, "History pruned"),
+                    Err(e) => error!(error = %e, "Couldn't prune the history"),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries matching the query, from the oldest to the most recent
+    ///
+    /// # Errors
+    /// Returns an `Error` if the glob pattern is invalid or the database can't be read
+    pub fn query(&self, query: &Query) -> Result<Vec<Entry>, Error> {
+        let glob = query.glob.as_deref().map(PathGlob::new).transpose()?;
+
+        let mut sql = String::from(
+            "SELECT time, kind, detail, paths, command, exit_code, duration FROM events WHERE time >= ? AND time < ?",
+        );
+        if !query.kinds.is_empty() {
+            let placeholders = vec!["?"; query.kinds.len()].join(", ");
+            sql.push_str(&format!(" AND kind IN ({})", placeholders));
+        }
+        sql.push_str(" ORDER BY time DESC, id DESC");
+
+        let mut params: Vec<rusqlite::types::Value> = vec![
+            query.since.map_or(i64::MIN, to_micros).into(),
+            query.until.map_or(i64::MAX, to_micros).into(),
+        ];
+        params.extend(query.kinds.iter().map(|kind| kind.clone().into()));
+
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&sql).map_err(Error::other)?;
+        let rows = statement
+            .query_map(params_from_iter(params), |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<i32>>(5)?,
+                    row.get::<_, Option<i64>>(6)?,
+                ))
+            })
+            .map_err(Error::other)?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (time, kind, detail, paths, command, exit_code, duration) =
+                row.map_err(Error::other)?;
+            let paths: Vec<PathBuf> = serde_json::from_str(&paths)?;
+            if let Some(glob) = &glob {
+                if !paths.iter().any(|path| glob.is_match(path, path)) {
+                    continue;
+                }
+            }
+            entries.push(Entry {
+                time: from_micros(time),
+                kind,
+                detail,
+                paths,
+                command,
+                exit_code,
+                duration: duration.map(|micros| Duration::from_micros(micros.max(0) as u64)),
+            });
+            if query.limit.is_some_and(|limit| entries.len() >= limit) {
+                break;
+            }
+        }
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Delete the entries recorded before `before`, returning the number of deleted entries
+    ///
+    /// # Errors
+    /// Returns an `Error` if the database can't be written
+    pub fn prune(&self, before: SystemTime) -> Result<usize, Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM events WHERE time < ?1",
+                params![to_micros(before)],
+            )
+            .map_err(Error::other)
+    }
+}
+
+fn to_micros(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as i64)
+        .unwrap_or(0)
+}
+
+fn from_micros(micros: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_micros(micros.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_record_query_and_prune() {
+        let dir = TempDir::new("history");
+        let history = History::open(&dir.join("history.db")).unwrap();
+
+        let start = UNIX_EPOCH + Duration::from_secs(1_714_564_800);
+        let entry = |minutes: u64, kind: &str, path: &str| Entry {
+            time: start + Duration::from_secs(minutes * 60),
+            kind: kind.to_string(),
+            detail: String::from("Any"),
+            paths: vec![PathBuf::from(path)],
+            command: Some(format!("make {}", path)),
+            exit_code: Some(0),
+            duration: Some(Duration::from_millis(1500)),
+        };
+        let entries = [
+            entry(0, "create", "/docs/a.md"),
+            entry(10, "modify", "/docs/a.md"),
+            entry(20, "modify", "/src/main.rs"),
+            entry(30, "remove", "/docs/b.md"),
+        ];
+        for entry in &entries {
+            history.record(entry).unwrap();
+        }
+
+        let query = |query: Query| history.query(&query).unwrap();
+        assert_eq!(query(Query::default()), entries.to_vec());
+        let markdown = Query {
+            since: Some(start + Duration::from_secs(5 * 60)),
+            glob: Some(String::from("*.md")),
+            kinds: vec![String::from("modify"), String::from("remove")],
+            ..Query::default()
+        };
+        assert_eq!(
+            query(markdown.clone()),
+            vec![entries[1].clone(), entries[3].clone()]
+        );
+        let latest = Query {
+            limit: Some(1),
+            ..markdown
+        };
+        assert_eq!(query(latest), vec![entries[3].clone()]);
+
+        assert_eq!(
+            history.prune(start + Duration::from_secs(15 * 60)).unwrap(),
+            2
+        );
+        assert_eq!(query(Query::default()), entries[2..].to_vec());
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::VecDeque;
+use std::io::Error;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use notify::Event;
+
+use crate::event::{matches_filter, WatchEvent};
+use crate::session::Session;
+use crate::watch::Watch;
+
+/// Blocking iterator over the filtered events of a watch
+///
+/// Events go through the same startup events, rescans and events filter as with `Watch::start`, but are consumed in the caller's own loop.
+/// The state file is saved periodically, with the events returned so far, and when the iterator is dropped.
+/// No signal handler is installed, the caller stays in charge of the shutdown.
+///
+/// # Examples
+///
+/// **Consume the events in a loop**
+///
+/// ```no_run
+/// use std::path::Path;
+/// use watchcrab::watch::Watch;
+///
+/// let events = vec!["create".to_string(), "modify".to_string()];
+///
+/// for event in Watch::source(Path::new("./"), true, &events).events().unwrap() {
+///     match event {
+///         Ok(event) => println!("{} {:?}", event.kind, event.path()),
+///         Err(e) => eprintln!("Watch error: {}", e),
+///     }
+/// }
+/// ```
+///
+/// **Integrate the raw receiver in a select loop**
+///
+/// ```no_run
+/// use std::path::Path;
+/// use crossbeam_channel::{select, tick};
+/// use std::time::Duration;
+/// use watchcrab::watch::Watch;
+///
+/// let events = vec!["all".to_string()];
+/// let mut watch_events = Watch::source(Path::new("./"), true, &events).events().unwrap();
+/// let receiver = watch_events.receiver().clone();
+/// let heartbeat = tick(Duration::from_secs(1));
+///
+/// loop {
+///     select! {
+///         recv(receiver) -> raw => {
+///             let Ok(raw) = raw else { break };
+///             for event in watch_events.process(raw) {
+///                 println!("{:?}", event);
+///             }
+///         }
+///         recv(heartbeat) -> _ => println!("still watching"),
+///     }
+/// }
+/// ```
+pub struct Events {
+    session: Session,
+    events_filter: Vec<String>,
+    // Events ready to be returned: startup events and the events synthesized by a rescan
+    pending: VecDeque<Result<Event, notify::Error>>,
+}
+
+impl Watch<'_> {
+    /// Start the watchers and return a blocking iterator over the filtered events instead of calling the handler
+    ///
+    /// The handler and the number of threads of the watch are not used, see `Watch::source` to create a watch without them.
+    ///
+    /// # Errors
+    /// Returns an `Error` if the watchers fail to initialize or the startup events can't be computed
+    pub fn events(&self) -> Result<Events, Error> {
+        let mut session = Session::open(self.source_config())?;
+        let pending = session.startup()?.into_iter().map(Ok).collect();
+        Ok(Events {
+            session,
+            events_filter: self.events_filter().to_vec(),
+            pending,
+        })
+    }
+}
+
+impl Events {
+    /// Block until the next filtered event
+    ///
+    /// Returns `None` once every watcher stopped.
+    pub fn recv(&mut self) -> Option<Result<WatchEvent, Error>> {
+        self.recv_deadline(None).ok()
+    }
+
+    /// Wait for the next filtered event for at most `timeout`
+    ///
+    /// # Errors
+    /// Returns `RecvTimeoutError::Timeout` if no event passed the filter in time and `RecvTimeoutError::Disconnected` once every watcher stopped
+    pub fn recv_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Result<WatchEvent, Error>, RecvTimeoutError> {
+        self.recv_deadline(Some(Instant::now() + timeout))
+    }
+
+    /// Raw events sent by the watchers, before rescans and filters
+    ///
+    /// Useful to wait for events in an existing `select!` loop. The events received from it must be passed to `Events::process`
+    /// to apply the rescans and the events filter.
+    pub fn receiver(&self) -> &Receiver<Result<Event, notify::Error>> {
+        self.session.receiver()
+    }
+
+    /// Turn a raw event received from `Events::receiver` into the filtered events to handle
+    ///
+    /// Events rejected by the filter are dropped, a rescan request is expanded into the rescan event and the synthesized events.
+    pub fn process(
+        &mut self,
+        event_result: Result<Event, notify::Error>,
+    ) -> Vec<Result<WatchEvent, Error>> {
+        self.session
+            .handle(event_result)
+            .into_iter()
+            .filter_map(|event_result| self.accept(event_result))
+            .collect()
+    }
+
+    fn recv_deadline(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<Result<WatchEvent, Error>, RecvTimeoutError> {
+        loop {
+            while let Some(event_result) = self.pending.pop_front() {
+                if let Some(event) = self.accept(event_result) {
+                    return Ok(event);
+                }
+            }
+            let event_result = self.session.recv_deadline(deadline)?;
+            self.pending.extend(self.session.handle(event_result));
+        }
+    }
+
+    /// Apply the events filter to an event handled by the session
+    fn accept(
+        &self,
+        event_result: Result<Event, notify::Error>,
+    ) -> Option<Result<WatchEvent, Error>> {
+        match event_result {
+            Ok(event) if matches_filter(&event, &self.events_filter) => {
+                // Returned to the caller, the event counts as handled in the state file
+                self.session.handled(&event);
+                Some(Ok(WatchEvent::new(event)))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(Error::other(e))),
+        }
+    }
+}
+
+impl Iterator for Events {
+    type Item = Result<WatchEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+impl Drop for Events {
+    fn drop(&mut self) {
+        self.session.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_events_recv_timeout() {
+        let dir = TempDir::new("iter");
+
+        let events = vec!["create".to_string()];
+        let mut watch_events = Watch::source(&dir, false, &events).events().unwrap();
+
+        assert_eq!(
+            watch_events
+                .recv_timeout(Duration::from_millis(50))
+                .unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+
+        fs::write(dir.join("new.txt"), "").unwrap();
+        let event = watch_events
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.kind, "create");
+        assert!(event.path().unwrap().ends_with("new.txt"));
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{Error, ErrorKind};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+use crate::sink::record_fields;
+
+/// Socket of the native protocol of systemd-journald
+pub const DEFAULT_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Priority of the entries, "informational"
+const PRIORITY: &str = "6";
+
+/// Sink sending each record as a journal entry with the native protocol of systemd-journald
+///
+/// The record is the `MESSAGE` of the entry, and each field of the JSON record becomes a `WATCHCRAB_<FIELD>` field,
+/// so the entries can be filtered with `journalctl WATCHCRAB_KIND='Create(File)'`.
+/// Entries larger than a datagram (around 200 KB) can't be sent.
+pub struct Journald {
+    #[cfg(unix)]
+    socket: UnixDatagram,
+    identifier: String,
+}
+
+impl Journald {
+    /// Open a socket to journald, `SYSLOG_IDENTIFIER` of the entries being `identifier`
+    ///
+    /// # Errors
+    /// Returns an `Error` if the socket can't be opened, or the platform doesn't have Unix domain sockets
+    pub fn connect(path: &Path, identifier: &str) -> Result<Journald, Error> {
+        #[cfg(unix)]
+        {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            Ok(Journald {
+                socket,
+                identifier: identifier.to_string(),
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (path, identifier);
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "journald is not supported on this platform",
+            ))
+        }
+    }
+
+    /// Send a record as an entry
+    ///
+    /// # Errors
+    /// Returns an `Error` if the entry can't be sent
+    pub fn send(&self, record: &str) -> Result<(), Error> {
+        let entry = format_entry(&self.identifier, record);
+        #[cfg(unix)]
+        {
+            let sent = self.socket.send(&entry)?;
+            if sent < entry.len() {
+                return Err(Error::new(ErrorKind::WriteZero, "Entry truncated"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serialize a record as the fields of an entry
+fn format_entry(identifier: &str, record: &str) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(record.len() * 2);
+    append_field(&mut entry, "MESSAGE", record);
+    append_field(&mut entry, "PRIORITY", PRIORITY);
+    append_field(&mut entry, "SYSLOG_IDENTIFIER", identifier);
+    for (name, value) in record_fields(record) {
+        append_field(&mut entry, &field_name(&name), &value);
+    }
+    entry
+}
+
+/// Append a field as `NAME=value\n`, or as `NAME\n<64-bit little endian length><value>\n` if the value contains a newline
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Field names are uppercase ASCII letters, digits and underscores, the prefix keeps them from starting with a digit
+fn field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    format!("WATCHCRAB_{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::json_record;
+    use crate::test_util::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_entry_to_socket() {
+        let dir = TempDir::new("journald");
+        let path = dir.join("socket");
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        let journald = Journald::connect(&path, "watchcrab").unwrap();
+        journald
+            .send(r#"{"stdout": "line 1\nline 2", "exit-code": 0}"#)
+            .unwrap();
+
+        let mut buf = [0; 1024];
+        let len = listener.recv(&mut buf).unwrap();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(
+            b"MESSAGE={\"stdout\": \"line 1\\nline 2\", \"exit-code\": 0}\nPRIORITY=6\nSYSLOG_IDENTIFIER=watchcrab\n",
+        );
+        expected.extend_from_slice(b"WATCHCRAB_EXIT_CODE=0\nWATCHCRAB_STDOUT\n");
+        expected.extend_from_slice(&13u64.to_le_bytes());
+        expected.extend_from_slice(b"line 1\nline 2\n");
+        assert_eq!(&buf[..len], expected.as_slice());
+    }
+
+    #[test]
+    fn test_entry_of_a_record_with_special_characters() {
+        let record = json_record(&[("stdout", "say \"hi\"\nC:\\tmp"), ("stderr", "")]);
+        let entry = format_entry("watchcrab", &record);
+
+        let mut expected = Vec::new();
+        append_field(&mut expected, "MESSAGE", &record);
+        append_field(&mut expected, "PRIORITY", PRIORITY);
+        append_field(&mut expected, "SYSLOG_IDENTIFIER", "watchcrab");
+        expected.extend_from_slice(b"WATCHCRAB_STDERR=\nWATCHCRAB_STDOUT\n");
+        expected.extend_from_slice(&15u64.to_le_bytes());
+        expected.extend_from_slice(b"say \"hi\"\nC:\\tmp\n");
+        assert_eq!(entry, expected);
+    }
+}
@@ -0,0 +1,812 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{never, select, unbounded, Receiver, Sender};
+use notify::Event;
+use tracing::{info, warn};
+
+use crate::event::kind_name;
+use crate::handler::{
+    is_reported, panic_message, print_failure, Decision, Handler, HandlerFailure, Reporter,
+    SkipReason,
+};
+
+/// Middleware wrapping a handler, to add behavior in front of it without changing it
+///
+/// Layers are added to a watch with `Watch::layer`. Any `Fn(Handler) -> Handler` closure is a layer.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// use notify::Event;
+/// use watchcrab::handler::Handler;
+/// use watchcrab::layer::{Debounce, Filter, Logging};
+/// use watchcrab::watch::Watch;
+///
+/// let events = vec!["all".to_string()];
+/// let f = Arc::new(Box::new(|_: Event| println!("rebuild")) as Box<dyn Fn(Event) + Send + Sync + 'static>);
+///
+/// Watch::new(Path::new("./"), true, &events, f, 1)
+///     .layer(Filter::new(|event: &Event| !event.paths.iter().any(|path| path.ends_with(".git"))))
+///     .layer(Debounce::new(Duration::from_millis(200)))
+///     .layer(Logging)
+///     .layer(|inner: Handler| -> Handler {
+///         Arc::new(move |event| {
+///             println!("custom layer");
+///             inner(event)
+///         })
+///     })
+///     .start();
+/// ```
+pub trait Layer {
+    /// Wrap `inner` in a new handler
+    fn layer(&self, inner: Handler) -> Handler;
+}
+
+impl<F> Layer for F
+where
+    F: Fn(Handler) -> Handler,
+{
+    fn layer(&self, inner: Handler) -> Handler {
+        self(inner)
+    }
+}
+
+/// Source of the time of the layers waiting or limiting over time (`Debounce`, `Throttle` and `RateLimit`)
+///
+/// `SystemClock` is used by default, tests can replace it with a clock they advance themselves,
+/// like `testing::VirtualClock`, so the layers don't depend on the real time.
+pub trait Clock: Send + Sync {
+    /// Current time
+    fn now(&self) -> Instant;
+
+    /// Receiver getting a message once the clock reaches `deadline`
+    fn at(&self, deadline: Instant) -> Receiver<Instant>;
+
+    /// Block the thread until the clock reaches `deadline`
+    fn sleep_until(&self, deadline: Instant) {
+        let _ = self.at(deadline).recv();
+    }
+}
+
+/// Real time, from `Instant::now`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn at(&self, deadline: Instant) -> Receiver<Instant> {
+        crossbeam_channel::at(deadline)
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Only pass the events matching a predicate to the inner handler
+pub struct Filter<F> {
+    predicate: Arc<F>,
+    reporter: Reporter,
+}
+
+impl<F> Filter<F>
+where
+    F: Fn(&Event) -> bool + Send + Sync + 'static,
+{
+    pub fn new(predicate: F) -> Filter<F> {
+        Filter {
+            predicate: Arc::new(predicate),
+            reporter: Reporter::default(),
+        }
+    }
+
+    /// Call `on_skipped` with the events rejected by the predicate, with `SkipReason::Filter`
+    pub fn on_skipped<S>(mut self, on_skipped: S) -> Self
+    where
+        S: Fn(&Event, SkipReason) + Send + Sync + 'static,
+    {
+        self.reporter.on_skipped = Some(Arc::new(on_skipped));
+        self
+    }
+
+    /// Call `on_trace` with each decision taken on the events, see `Decision`
+    pub fn on_trace<T>(mut self, on_trace: T) -> Self
+    where
+        T: Fn(&Event, &Decision) + Send + Sync + 'static,
+    {
+        self.reporter.on_trace = Some(Arc::new(on_trace));
+        self
+    }
+}
+
+impl<F> Layer for Filter<F>
+where
+    F: Fn(&Event) -> bool + Send + Sync + 'static,
+{
+    fn layer(&self, inner: Handler) -> Handler {
+        let predicate = Arc::clone(&self.predicate);
+        let reporter = self.reporter.clone();
+        Arc::new(move |event| {
+            if predicate(&event) {
+                inner(event)
+            } else {
+                reporter.report(&event, Decision::Skipped(SkipReason::Filter));
+                Ok(())
+            }
+        })
+    }
+}
+
+/// Handle the latest event of a set of paths once no other event of these paths was received for `delay`
+///
+/// A burst of events on the same file (an editor saving in several writes) is handled once, with the last event.
+/// The debounced events are handled one at a time by a dedicated thread, not by the thread pool of the watch: the
+/// number of threads and `SerializeBy` don't apply to them. In a watch, their failures are reported
+/// and counted like the others (see `Watch::on_error`), otherwise they are printed to stderr.
+/// The events still waiting are handled when the watcher stops, before `Watch::start` returns.
+pub struct Debounce {
+    delay: Duration,
+    clock: Arc<dyn Clock>,
+    reporter: Reporter,
+}
+
+impl Debounce {
+    pub fn new(delay: Duration) -> Debounce {
+        Debounce {
+            delay,
+            clock: Arc::new(SystemClock),
+            reporter: Reporter::default(),
+        }
+    }
+
+    /// Call `on_skipped` with the events replaced by a later event of the same paths, with `SkipReason::Debounce`
+    pub fn on_skipped<S>(mut self, on_skipped: S) -> Self
+    where
+        S: Fn(&Event, SkipReason) + Send + Sync + 'static,
+    {
+        self.reporter.on_skipped = Some(Arc::new(on_skipped));
+        self
+    }
+
+    /// Call `on_trace` with each decision taken on the events, see `Decision`
+    pub fn on_trace<T>(mut self, on_trace: T) -> Self
+    where
+        T: Fn(&Event, &Decision) + Send + Sync + 'static,
+    {
+        self.reporter.on_trace = Some(Arc::new(on_trace));
+        self
+    }
+
+    /// Measure the delay with another clock than the system clock, see `Clock`
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+impl Layer for Debounce {
+    fn layer(&self, inner: Handler) -> Handler {
+        let (tx, rx) = unbounded();
+        let delay = self.delay;
+        let clock = Arc::clone(&self.clock);
+        let thread_clock = Arc::clone(&self.clock);
+        let reporter = self.reporter.clone();
+        let thread_reporter = self.reporter.clone();
+        let debouncer = Debouncer {
+            tx: Some(tx),
+            thread: Some(thread::spawn(move || {
+                debounce(rx, thread_clock, thread_reporter, inner)
+            })),
+        };
+        Arc::new(move |event| {
+            reporter.report(&event, Decision::Debounced(delay));
+            // The deadline is taken when the event is received, not when the thread gets to it
+            if let Some(tx) = &debouncer.tx {
+                let _ = tx.send((clock.now() + delay, event));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Sender to the debounce thread, waits for the pending events to be handled when dropped
+struct Debouncer {
+    tx: Option<Sender<(Instant, Event)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Debouncer {
+    fn drop(&mut self) {
+        self.tx.take(); // Disconnect the channel so the thread flushes the pending events
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn debounce(
+    rx: Receiver<(Instant, Event)>,
+    clock: Arc<dyn Clock>,
+    reporter: Reporter,
+    inner: Handler,
+) {
+    let mut pending: HashMap<Vec<PathBuf>, (Instant, Event)> = HashMap::new();
+    let replace =
+        |pending: &mut HashMap<Vec<PathBuf>, (Instant, Event)>, deadline, event: Event| {
+            if let Some((_, replaced)) = pending.insert(event.paths.clone(), (deadline, event)) {
+                reporter.report(&replaced, Decision::Skipped(SkipReason::Debounce));
+            }
+        };
+    let release = |event: Event| {
+        reporter.report(&event, Decision::Released);
+        call_detached(&inner, event);
+    };
+    loop {
+        let next_deadline = pending.values().map(|(deadline, _)| *deadline).min();
+        let timeout = match next_deadline {
+            Some(deadline) => clock.at(deadline),
+            None => never(),
+        };
+        select! {
+            recv(rx) -> received => match received {
+                Ok((deadline, event)) => replace(&mut pending, deadline, event),
+                Err(_) => {
+                    let mut due: Vec<(Instant, Event)> = pending.into_values().collect();
+                    due.sort_by_key(|(deadline, _)| *deadline);
+                    for (_, event) in due {
+                        release(event);
+                    }
+                    return;
+                }
+            },
+            recv(timeout) -> _ => {
+                // Events received before the deadline replace the pending ones of their paths
+                while let Ok((deadline, event)) = rx.try_recv() {
+                    replace(&mut pending, deadline, event);
+                }
+                let now = clock.now();
+                let mut due: Vec<(Instant, Event)> = Vec::new();
+                pending.retain(|_, (deadline, event)| {
+                    if *deadline <= now {
+                        due.push((*deadline, event.clone()));
+                        false
+                    } else {
+                        true
+                    }
+                });
+                due.sort_by_key(|(deadline, _)| *deadline);
+                for (_, event) in due {
+                    release(event);
+                }
+            }
+        }
+    }
+}
+
+/// Call a handler outside of the watch dispatcher, printing the failures the dispatcher didn't report
+fn call_detached(handler: &Handler, event: Event) {
+    let failed_event = event.clone();
+    let failure = match catch_unwind(AssertUnwindSafe(|| handler(event))) {
+        Ok(Ok(())) => return,
+        Ok(Err(e)) if is_reported(&e) => return,
+        Ok(Err(e)) => HandlerFailure::Error(e),
+        Err(payload) => HandlerFailure::Panic(panic_message(payload)),
+    };
+    print_failure(&failed_event, &failure);
+}
+
+/// What a throttling layer does with the events exceeding its limit
+///
+/// * `Queue` - The event waits until it can be handled, the events received meanwhile wait in the event queue
+/// * `Drop` - The event is dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottlePolicy {
+    Queue,
+    Drop,
+}
+
+impl FromStr for ThrottlePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(ThrottlePolicy::Queue),
+            "drop" => Ok(ThrottlePolicy::Drop),
+            _ => Err(format!(
+                "Invalid throttle policy '{}', expected one of: queue, drop",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ThrottlePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThrottlePolicy::Queue => write!(f, "queue"),
+            ThrottlePolicy::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Handle at most one event per path every `interval`
+///
+/// The first event of a path is handled right away (leading edge), unlike `Debounce`. The next events of the path
+/// within `interval` are dropped by default, or delayed to keep `interval` between two calls with `ThrottlePolicy::Queue`.
+pub struct Throttle {
+    interval: Duration,
+    policy: ThrottlePolicy,
+    clock: Arc<dyn Clock>,
+    reporter: Reporter,
+}
+
+impl Throttle {
+    pub fn new(interval: Duration) -> Throttle {
+        Throttle {
+            interval,
+            policy: ThrottlePolicy::Drop,
+            clock: Arc::new(SystemClock),
+            reporter: Reporter::default(),
+        }
+    }
+
+    /// Call `on_skipped` with the dropped events, with `SkipReason::Throttle`
+    pub fn on_skipped<S>(mut self, on_skipped: S) -> Self
+    where
+        S: Fn(&Event, SkipReason) + Send + Sync + 'static,
+    {
+        self.reporter.on_skipped = Some(Arc::new(on_skipped));
+        self
+    }
+
+    /// Call `on_trace` with each decision taken on the events, see `Decision`
+    pub fn on_trace<T>(mut self, on_trace: T) -> Self
+    where
+        T: Fn(&Event, &Decision) + Send + Sync + 'static,
+    {
+        self.reporter.on_trace = Some(Arc::new(on_trace));
+        self
+    }
+
+    /// Measure the interval with another clock than the system clock, see `Clock`
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Set what to do with the events of a path received within `interval`, `ThrottlePolicy::Drop` by default
+    pub fn policy(mut self, policy: ThrottlePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Layer for Throttle {
+    fn layer(&self, inner: Handler) -> Handler {
+        let interval = self.interval;
+        let policy = self.policy;
+        let clock = Arc::clone(&self.clock);
+        let reporter = self.reporter.clone();
+        // Time of the last call of each path, or of the next one when a call is queued
+        let last_calls: Mutex<HashMap<Option<PathBuf>, Instant>> = Mutex::new(HashMap::new());
+        Arc::new(move |event| {
+            let key = event.paths.first().cloned();
+            let now = clock.now();
+            let call_at = {
+                let mut last_calls = last_calls.lock().unwrap();
+                let call_at = match last_calls.get(&key) {
+                    Some(last) if *last + interval > now => match policy {
+                        ThrottlePolicy::Drop => {
+                            reporter.report(&event, Decision::Skipped(SkipReason::Throttle));
+                            return Ok(());
+                        }
+                        ThrottlePolicy::Queue => *last + interval,
+                    },
+                    _ => now,
+                };
+                last_calls.insert(key, call_at);
+                // Forget the paths that can't be throttled anymore so the map does not grow forever
+                if last_calls.len() > 1024 {
+                    last_calls.retain(|_, last| *last + interval > now);
+                }
+                call_at
+            };
+            if call_at > now {
+                reporter.report(&event, Decision::Throttled(call_at - now));
+                clock.sleep_until(call_at);
+            }
+            inner(event)
+        })
+    }
+}
+
+/// Handle at most `max` events every `per` across all paths, with bursts of up to `burst` events (token bucket)
+///
+/// The events exceeding the limit wait by default, so the handler is called at the limited rate and the event queue
+/// absorbs the rest (see `Watch::overflow_policy`), or are dropped with `ThrottlePolicy::Drop`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// use notify::Event;
+/// use watchcrab::layer::RateLimit;
+/// use watchcrab::watch::Watch;
+///
+/// let events = vec!["create".to_string()];
+/// let f = Arc::new(Box::new(|_: Event| println!("convert")) as Box<dyn Fn(Event) + Send + Sync + 'static>);
+///
+/// // At most 5 conversions per second, even if thousands of files are dropped at once
+/// Watch::new(Path::new("./"), false, &events, f, 4)
+///     .layer(RateLimit::new(5, Duration::from_secs(1)))
+///     .start();
+/// ```
+pub struct RateLimit {
+    max: u32,
+    per: Duration,
+    burst: u32,
+    policy: ThrottlePolicy,
+    clock: Arc<dyn Clock>,
+    reporter: Reporter,
+}
+
+impl RateLimit {
+    /// Handle at most `max` events every `per`, a `max` of 0 is raised to 1 so the events are never held forever
+    pub fn new(max: u32, per: Duration) -> RateLimit {
+        let max = max.max(1);
+        RateLimit {
+            max,
+            per,
+            burst: max,
+            policy: ThrottlePolicy::Queue,
+            clock: Arc::new(SystemClock),
+            reporter: Reporter::default(),
+        }
+    }
+
+    /// Call `on_skipped` with the dropped events, with `SkipReason::RateLimit`
+    pub fn on_skipped<S>(mut self, on_skipped: S) -> Self
+    where
+        S: Fn(&Event, SkipReason) + Send + Sync + 'static,
+    {
+        self.reporter.on_skipped = Some(Arc::new(on_skipped));
+        self
+    }
+
+    /// Call `on_trace` with each decision taken on the events, see `Decision`
+    pub fn on_trace<T>(mut self, on_trace: T) -> Self
+    where
+        T: Fn(&Event, &Decision) + Send + Sync + 'static,
+    {
+        self.reporter.on_trace = Some(Arc::new(on_trace));
+        self
+    }
+
+    /// Refill the tokens with another clock than the system clock, see `Clock`
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Set the number of events that can be handled at once after a quiet period, `max` by default
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    /// Set what to do with the events exceeding the limit, `ThrottlePolicy::Queue` by default
+    pub fn policy(mut self, policy: ThrottlePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+/// Tokens available and time of the last refill of a `RateLimit`
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Layer for RateLimit {
+    fn layer(&self, inner: Handler) -> Handler {
+        let rate = self.max as f64 / self.per.as_secs_f64().max(f64::EPSILON);
+        let burst = self.burst as f64;
+        let policy = self.policy;
+        let clock = Arc::clone(&self.clock);
+        let reporter = self.reporter.clone();
+        let bucket = Mutex::new(TokenBucket {
+            tokens: burst,
+            refilled_at: clock.now(),
+        });
+        Arc::new(move |event| {
+            let mut delayed = false;
+            loop {
+                let wait_until = {
+                    let mut bucket = bucket.lock().unwrap();
+                    let now = clock.now();
+                    let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * rate;
+                    bucket.tokens = (bucket.tokens + refill).min(burst);
+                    bucket.refilled_at = now;
+                    if bucket.tokens >= 1.0 {
+                        bucket.tokens -= 1.0;
+                        break;
+                    }
+                    if policy == ThrottlePolicy::Drop {
+                        reporter.report(&event, Decision::Skipped(SkipReason::RateLimit));
+                        return Ok(());
+                    }
+                    let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / rate);
+                    // Another event can take the refilled token first, the delay is only reported once
+                    if !delayed {
+                        delayed = true;
+                        reporter.report(&event, Decision::RateLimited(wait));
+                    }
+                    now + wait
+                };
+                clock.sleep_until(wait_until);
+            }
+            inner(event)
+        })
+    }
+}
+
+/// Counters of the calls of the inner handler of a `Metrics` layer
+#[derive(Debug, Default)]
+pub struct MetricsStats {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl MetricsStats {
+    /// Number of events handled
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    /// Number of events whose handler returned an error
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Total time spent in the handler
+    pub fn total_duration(&self) -> Duration {
+        Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Longest time spent handling an event
+    pub fn max_duration(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Mean time spent handling an event, zero if no event was handled
+    pub fn mean_duration(&self) -> Duration {
+        match self.calls() {
+            0 => Duration::ZERO,
+            calls => Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed) / calls),
+        }
+    }
+}
+
+/// Count the calls and errors of the inner handler and measure their duration
+pub struct Metrics {
+    stats: Arc<MetricsStats>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            stats: Arc::new(MetricsStats::default()),
+        }
+    }
+
+    /// Counters shared with the handlers wrapped by this layer
+    pub fn stats(&self) -> Arc<MetricsStats> {
+        Arc::clone(&self.stats)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Layer for Metrics {
+    fn layer(&self, inner: Handler) -> Handler {
+        let stats = Arc::clone(&self.stats);
+        Arc::new(move |event| {
+            let start = Instant::now();
+            let result = inner(event);
+            let nanos = start.elapsed().as_nanos() as u64;
+
+            stats.calls.fetch_add(1, Ordering::Relaxed);
+            if result.is_err() {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            stats.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+            stats.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+            result
+        })
+    }
+}
+
+/// Log each event handled by the inner handler, with its duration and result
+pub struct Logging;
+
+impl Layer for Logging {
+    fn layer(&self, inner: Handler) -> Handler {
+        Arc::new(move |event| {
+            let kind = kind_name(&event);
+            let paths = event.paths.clone();
+            let start = Instant::now();
+            let result = inner(event);
+            match &result {
+                Ok(()) => info!(kind, ?paths, elapsed = ?start.elapsed(), "Event handled"),
+                Err(e) => {
+                    warn!(kind, ?paths, elapsed = ?start.elapsed(), error = %e, "Event handling failed")
+                }
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::VirtualClock;
+    use notify::event::{CreateKind, ModifyKind, RemoveKind};
+    use notify::EventKind;
+
+    fn recorder() -> (Handler, Arc<Mutex<Vec<PathBuf>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let handler: Handler = {
+            let calls = Arc::clone(&calls);
+            Arc::new(move |event: Event| {
+                calls.lock().unwrap().push(event.paths[0].clone());
+                Ok(())
+            })
+        };
+        (handler, calls)
+    }
+
+    fn modify(path: &str) -> Event {
+        Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path.into())
+    }
+
+    #[test]
+    fn test_filter_throttle_and_metrics() {
+        let (handler, calls) = recorder();
+        let metrics = Metrics::new();
+        let handler = metrics.layer(handler);
+        let skipped = Arc::new(Mutex::new(Vec::new()));
+        let on_skipped = {
+            let skipped = Arc::clone(&skipped);
+            move |event: &Event, reason: SkipReason| {
+                skipped
+                    .lock()
+                    .unwrap()
+                    .push((event.paths[0].clone(), reason));
+            }
+        };
+        let handler = Throttle::new(Duration::from_secs(60))
+            .on_skipped(on_skipped.clone())
+            .layer(handler);
+        let handler = Filter::new(|event: &Event| !event.paths[0].ends_with("skip"))
+            .on_skipped(on_skipped)
+            .layer(handler);
+
+        for path in ["/a", "/a", "/b", "/skip"] {
+            handler(modify(path)).unwrap();
+        }
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![PathBuf::from("/a"), "/b".into()]
+        );
+        assert_eq!(
+            *skipped.lock().unwrap(),
+            vec![
+                (PathBuf::from("/a"), SkipReason::Throttle),
+                ("/skip".into(), SkipReason::Filter)
+            ]
+        );
+        assert_eq!(metrics.stats().calls(), 2);
+        assert_eq!(metrics.stats().errors(), 0);
+    }
+
+    #[test]
+    fn test_rate_limit_drops_or_delays_the_events_over_the_limit() {
+        let (handler, calls) = recorder();
+        let handler = RateLimit::new(2, Duration::from_secs(60))
+            .policy(ThrottlePolicy::Drop)
+            .layer(handler);
+        for path in ["/a", "/b", "/c"] {
+            handler(modify(path)).unwrap();
+        }
+        assert_eq!(calls.lock().unwrap().len(), 2);
+
+        let (handler, calls) = recorder();
+        let handler = RateLimit::new(20, Duration::from_secs(1))
+            .burst(1)
+            .layer(handler);
+        let start = Instant::now();
+        for path in ["/a", "/b", "/c"] {
+            handler(modify(path)).unwrap();
+        }
+        assert_eq!(calls.lock().unwrap().len(), 3);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+
+        // A limit of 0 is raised to 1 instead of holding every event
+        let (handler, calls) = recorder();
+        let handler = RateLimit::new(0, Duration::from_secs(60))
+            .policy(ThrottlePolicy::Drop)
+            .layer(handler);
+        for path in ["/a", "/b"] {
+            handler(modify(path)).unwrap();
+        }
+        assert_eq!(*calls.lock().unwrap(), vec![PathBuf::from("/a")]);
+    }
+
+    #[test]
+    fn test_debounce_keeps_the_last_event_of_a_burst() {
+        let (tx, rx) = unbounded();
+        let handler: Handler = Arc::new(move |event: Event| {
+            let _ = tx.send((event.paths[0].clone(), kind_name(&event)));
+            Ok(())
+        });
+        let clock = VirtualClock::new();
+        let handler = Debounce::new(Duration::from_millis(50))
+            .clock(clock.clone())
+            .layer(handler);
+
+        let event = |kind, path: &str| Event::new(kind).add_path(path.into());
+        handler(event(EventKind::Create(CreateKind::File), "/a")).unwrap();
+        handler(event(EventKind::Modify(ModifyKind::Any), "/b")).unwrap();
+        handler(event(EventKind::Remove(RemoveKind::File), "/a")).unwrap();
+
+        clock.advance(Duration::from_millis(40));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        clock.advance(Duration::from_millis(10));
+        let timeout = Duration::from_secs(5);
+        let mut handled = vec![
+            rx.recv_timeout(timeout).unwrap(),
+            rx.recv_timeout(timeout).unwrap(),
+        ];
+        handled.sort();
+        assert_eq!(
+            handled,
+            vec![(PathBuf::from("/a"), "remove"), ("/b".into(), "modify")]
+        );
+        drop(handler);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -9,5 +9,31 @@
 //Re-export the main functions for the crate
 pub use self::watch::Watch;
 
+pub mod backend;
+pub mod event;
+pub mod executor;
+pub mod handler;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod iter;
+pub mod journald;
+pub mod layer;
+pub mod queue;
+pub mod replay;
+pub mod rotate;
+pub mod router;
+pub mod scan;
+pub mod server;
+mod session;
+pub mod sink;
+pub mod state;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod syslog;
+#[cfg(test)]
+mod test_util;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod util;
 pub mod watch;
+pub mod webhook;
@@ -1,9 +1,25 @@
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "history")]
+use std::io::Write;
+use std::io::{Error, ErrorKind, IsTerminal};
 use std::path::{Path, PathBuf};
 use std::process::Child;
 use std::sync::Arc;
+use std::thread;
+#[cfg(feature = "history")]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
-use notify::Event;
+use clap::{Parser, Subcommand};
+use crossbeam_channel::{bounded, select, Receiver};
+use notify::{Event, EventKind};
+#[cfg(target_family = "unix")]
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, info, warn};
 
 #[cfg(target_family = "unix")]
 use watchcrab::util::command_exec_unix as command_exec;
@@ -11,13 +27,70 @@ use watchcrab::util::command_exec_unix as command_exec;
 #[cfg(target_family = "windows")]
 use watchcrab::util::command_exec_windows as command_exec;
 
-use watchcrab::util::{parse_command, write_to_log_file, write_to_log_file_async};
+use watchcrab::backend::Backend;
+use watchcrab::event::EventRecord;
+use watchcrab::executor::{KeyedExecutor, SerializeBy};
+use watchcrab::handler::{
+    Accounting, Decision, HandlerError, HandlerFailure, HandlerStats, SkipCallback, SkipReason,
+};
+#[cfg(feature = "history")]
+use watchcrab::history::{Entry, History, Query};
+use watchcrab::journald::{self, Journald};
+use watchcrab::layer::{Debounce, RateLimit, Throttle, ThrottlePolicy};
+use watchcrab::queue::{OverflowPolicy, DEFAULT_QUEUE_CAPACITY};
+use watchcrab::replay::{Recorder, Replay};
+use watchcrab::rotate::{parse_size, Rotation, RotationOptions, DEFAULT_RETAIN};
+use watchcrab::server::{
+    Server, ServerAddr, ServerOptions, SlowClientPolicy, Subscriber, Subscription,
+    DEFAULT_CLIENT_BUFFER,
+};
+use watchcrab::sink::{json_record, LogWriter, Sink, WriterOptions};
+use watchcrab::syslog::{Facility, Syslog, SyslogAddr, SyslogOptions};
+use watchcrab::util::parse_command;
+#[cfg(feature = "history")]
+use watchcrab::util::{format_timestamp, parse_duration, parse_time};
+use watchcrab::webhook::{parse_header, Webhook, WebhookOptions, DEFAULT_MAX_RETRIES};
 use watchcrab::Watch;
 
 /// Simple command line tool to watch a directory for changes and execute a command when an event is triggered
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    watch: WatchArgs,
+
+    #[command(flatten)]
+    handler: HandlerArgs,
+
+    #[command(flatten)]
+    pipeline: PipelineArgs,
+
+    /// Level of the diagnostics written to stderr: "off", "error", "warn", "info", "debug" or "trace", stdout only receives the event records
+    #[arg(long, global = true, default_value_t = LevelFilter::INFO)]
+    log_level: LevelFilter,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Stream the events as NDJSON to the clients connected to a Unix domain socket or TCP address
+    Serve(ServeArgs),
+    /// Run the command, or write a record, for each event received from a watchcrab server
+    Subscribe(Box<SubscribeArgs>),
+    /// Write the events to a session file with their timings, to replay them later
+    Record(RecordArgs),
+    /// Run the command, or write a record, for each event of a session file written by the record subcommand
+    Replay(Box<ReplayArgs>),
+    /// List the events recorded with --history, or prune them
+    #[cfg(feature = "history")]
+    History(HistoryArgs),
+}
+
+/// Options shared by every command watching a directory
+#[derive(clap::Args, Debug)]
+struct WatchArgs {
     /// Path to watch
     #[arg(short = 'p', long, default_value_t = String::from("./"))]
     path: String,
@@ -30,6 +103,198 @@ struct Args {
     #[arg(short = 'e', long, num_args = 1.., value_delimiter = ' ', default_values = &["all"])]
     events: Vec<String>,
 
+    /// Filesystem notification backend: "native", "poll" (for NFS/SMB mounts and containers) or "auto" (native with fallback to polling)
+    #[arg(short = 'b', long, default_value_t = Backend::Native)]
+    backend: Backend,
+
+    /// Interval in milliseconds between two scans when using the polling backend
+    #[arg(long, default_value_t = 1000)]
+    poll_interval: u64,
+
+    /// Compare file contents when using the polling backend to detect changes that keep the same modification time
+    #[arg(long, default_value_t = false)]
+    compare_contents: bool,
+
+    /// Handle the files already present in the directory as "create" events before watching for new events
+    #[arg(long, default_value_t = false)]
+    initial_scan: bool,
+
+    /// State file used to catch up on the changes made while watchcrab was stopped, by default no state is kept
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Interval in seconds between two saves of the state file
+    #[arg(long, default_value_t = 60)]
+    state_interval: u64,
+
+    /// Store a hash of the contents of each file in the state file to detect changes that keep the same size and modification time
+    #[arg(long, default_value_t = false)]
+    state_hash: bool,
+
+    /// Keep a snapshot of the directory to recover the events lost when the event queue overflows
+    #[arg(long, default_value_t = false)]
+    rescan_on_overflow: bool,
+
+    /// Maximum number of events waiting to be handled, 0 for no limit
+    #[arg(long, default_value_t = DEFAULT_QUEUE_CAPACITY)]
+    queue_capacity: usize,
+
+    /// What to do with new events when the queue is full: "block", "drop-oldest", "drop-newest" or "coalesce" (merge the waiting events of each path)
+    #[arg(long, default_value_t = OverflowPolicy::Block)]
+    overflow_policy: OverflowPolicy,
+}
+
+impl WatchArgs {
+    /// Watch of the directory, configured from the command line, running `f` for each event
+    fn watch<F, E>(&self, f: F, threads: usize) -> Watch<'_>
+    where
+        F: Fn(Event) -> Result<(), E> + Send + Sync + 'static,
+        E: Into<HandlerError>,
+    {
+        let path = Path::new(&self.path);
+
+        match path {
+            _ if !path.exists() => {
+                panic!("Path does not exist");
+            }
+            _ if !path.is_dir() => {
+                panic!("Path is not a directory");
+            }
+            _ => (),
+        }
+
+        let watchcrab_watch = Watch::new_fallible(path, self.recursive, &self.events, f, threads)
+            .backend(self.backend)
+            .poll_interval(Duration::from_millis(self.poll_interval))
+            .compare_contents(self.compare_contents)
+            .initial_scan(self.initial_scan)
+            .state_interval(Duration::from_secs(self.state_interval))
+            .hash_contents(self.state_hash)
+            .rescan_on_overflow(self.rescan_on_overflow)
+            .queue_capacity(self.queue_capacity)
+            .overflow_policy(self.overflow_policy);
+        match &self.state_file {
+            Some(state_file) => watchcrab_watch.state_file(state_file),
+            None => watchcrab_watch,
+        }
+    }
+}
+
+/// Options of the handling of the events, shared by the watch and the replay of a session
+#[derive(clap::Args, Debug)]
+struct PipelineArgs {
+    /// Number of threads to execute the command in, by default it will execute the command in the main thread
+    #[arg(short = 't', long, default_value_t = 1)]
+    threads: usize,
+
+    /// With --threads, run the commands of events sharing a key one at a time and in order: "none", "path" (same file) or "parent" (same directory)
+    #[arg(long, default_value_t = SerializeBy::None)]
+    serialize_by: SerializeBy,
+
+    /// Stop watchcrab after this number of consecutive failed commands (non-zero exit status), 0 to never stop
+    #[arg(long, default_value_t = 0)]
+    max_failures: u64,
+
+    /// Wait until no other event of the same file was received for this number of milliseconds, then run the command once for the last event.
+    /// The debounced commands run one at a time, --threads and --serialize-by don't apply to them
+    #[arg(long)]
+    debounce: Option<u64>,
+
+    /// Run the command at most once every this number of milliseconds for the same file
+    #[arg(long)]
+    throttle: Option<u64>,
+
+    /// Run at most this number of commands per second across all files
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+
+    /// With --rate-limit, number of commands that can run at once after a quiet period, by default the rate limit
+    #[arg(long, requires = "rate_limit", value_parser = clap::value_parser!(u32).range(1..))]
+    burst: Option<u32>,
+
+    /// What to do with the events over --throttle or --rate-limit: "queue" (wait) or "drop", by default --throttle drops and --rate-limit queues
+    #[arg(long)]
+    throttle_policy: Option<ThrottlePolicy>,
+
+    /// Log how each event is handled, at the info level: its raw and normalized kinds, the events filter, debounce, throttle and rate limit decisions, and where it is dispatched
+    #[arg(long)]
+    explain: bool,
+}
+
+impl PipelineArgs {
+    /// Add the options to a watch created with `self.threads` threads
+    ///
+    /// The timings of the layers are divided by `time_scale`, so a replay at a higher speed coalesces the events as they were live.
+    /// The events dropped by the events filter or a layer are passed to `on_skipped`, and every decision is logged with --explain.
+    fn apply<'a>(
+        &self,
+        watch: Watch<'a>,
+        time_scale: f64,
+        on_skipped: Option<SkipCallback>,
+    ) -> Watch<'a> {
+        let scale = |duration: Duration| duration.div_f64(time_scale);
+        let skipped = || {
+            let on_skipped = on_skipped.clone();
+            move |event: &Event, reason: SkipReason| {
+                if let Some(on_skipped) = &on_skipped {
+                    on_skipped(event, reason);
+                }
+            }
+        };
+        let mut watch = watch
+            .serialize_by(self.serialize_by)
+            .max_consecutive_failures(self.max_failures)
+            .on_error(warn_failure)
+            .on_skipped(skipped());
+        if self.explain {
+            watch = watch.on_trace(explain);
+        }
+        if let Some(delay) = self.debounce {
+            if self.threads > 1 {
+                warn!(
+                    threads = self.threads,
+                    "The commands debounced by --debounce run one at a time, not in the thread pool"
+                );
+            }
+            let mut debounce =
+                Debounce::new(scale(Duration::from_millis(delay))).on_skipped(skipped());
+            if self.explain {
+                debounce = debounce.on_trace(explain);
+            }
+            watch = watch.layer(debounce);
+        }
+        if let Some(interval) = self.throttle {
+            let mut throttle =
+                Throttle::new(scale(Duration::from_millis(interval))).on_skipped(skipped());
+            if let Some(policy) = self.throttle_policy {
+                throttle = throttle.policy(policy);
+            }
+            if self.explain {
+                throttle = throttle.on_trace(explain);
+            }
+            watch = watch.layer(throttle);
+        }
+        if let Some(max) = self.rate_limit {
+            let mut rate_limit =
+                RateLimit::new(max, scale(Duration::from_secs(1))).on_skipped(skipped());
+            if let Some(burst) = self.burst {
+                rate_limit = rate_limit.burst(burst);
+            }
+            if let Some(policy) = self.throttle_policy {
+                rate_limit = rate_limit.policy(policy);
+            }
+            if self.explain {
+                rate_limit = rate_limit.on_trace(explain);
+            }
+            watch = watch.layer(rate_limit);
+        }
+        watch
+    }
+}
+
+/// Options of the command run, or the record written, for each event
+#[derive(clap::Args, Debug)]
+struct HandlerArgs {
     /// shell command that will receive the --args as a string, by default it will use "sh -c" or "cmd /C" based on the OS
     #[arg(short = 's', long)]
     sh_cmd: Option<String>,
@@ -38,144 +303,801 @@ struct Args {
     #[arg(short = 'a', long, num_args = 1.., value_delimiter = ' ')]
     args: Option<Vec<String>>,
 
-    /// Number of threads to execute the command in, by default it will execute the command in the main thread
-    #[arg(short = 't', long, default_value_t = 1)]
-    threads: usize,
-
-    /// Output file to write logs to, by default it will print the logs to stdout
+    /// Output file to write logs to, by default it will print the logs to stdout. Repeat it to write to several files, "-" for stdout,
+    /// "syslog" (or "syslog:unix:<path>", "syslog:udp:<host>:<port>") for syslog, "journald" (or "journald:<path>") for the systemd journal
     #[arg(short, long)]
-    output: Option<String>,
+    output: Vec<String>,
+
+    /// Rotate the output file before it grows larger than this size, in bytes with an optional K, M or G suffix (e.g. 100M)
+    #[arg(long, requires = "output", value_parser = parse_size)]
+    rotate_size: Option<u64>,
+
+    /// Rotate the output file every "hourly" or "daily" period (UTC)
+    #[arg(long, requires = "output")]
+    rotate_every: Option<Rotation>,
+
+    /// Number of rotated output files to keep
+    #[arg(long, requires = "output", default_value_t = DEFAULT_RETAIN)]
+    retain: usize,
+
+    /// Compress the rotated output files with gzip
+    #[arg(long, requires = "output", default_value_t = false)]
+    compress: bool,
+
+    /// Interval in milliseconds between two syncs of the output files to the disk, 0 to leave it to the OS
+    #[arg(long, default_value_t = 1000)]
+    sync_interval: u64,
+
+    /// URL receiving a POST request with the records as JSON, in addition to the outputs
+    #[arg(long)]
+    webhook: Option<String>,
+
+    /// Header added to the webhook requests, in the format "Name: value", can be repeated
+    #[arg(long, requires = "webhook", value_parser = parse_header)]
+    webhook_header: Vec<(String, String)>,
+
+    /// Secret signing the body of the webhook requests with HMAC-SHA256, in the X-Watchcrab-Signature header
+    #[arg(long, requires = "webhook")]
+    webhook_secret: Option<String>,
+
+    /// Maximum number of records per webhook request, sent as a JSON array when greater than 1
+    #[arg(long, default_value_t = 1)]
+    webhook_batch: usize,
+
+    /// Timeout in milliseconds of a webhook request
+    #[arg(long, default_value_t = 10000)]
+    webhook_timeout: u64,
+
+    /// Number of retries of a failed webhook request, with an exponential backoff
+    #[arg(long, default_value_t = DEFAULT_MAX_RETRIES)]
+    webhook_retries: u32,
+
+    /// Directory keeping the webhook requests that could not be delivered, they are sent again once the endpoint is back
+    #[arg(long, requires = "webhook")]
+    webhook_spool: Option<PathBuf>,
+
+    /// Facility of the messages sent to syslog: "user", "daemon", "local0" to "local7", etc.
+    #[arg(long, default_value_t = Facility::USER)]
+    syslog_facility: Facility,
+
+    /// Write the command that would run for each event, with its shell, working directory and environment, instead of running it, and the events that were skipped with the reason
+    #[arg(long, requires = "args")]
+    dry_run: bool,
+
+    /// SQLite database recording every event and the result of its command, query it with the history subcommand
+    #[cfg(feature = "history")]
+    #[arg(long)]
+    history: Option<PathBuf>,
+
+    /// Delete the history entries older than this age, like "30d" or "12h"
+    #[cfg(feature = "history")]
+    #[arg(long, requires = "history", value_parser = parse_duration)]
+    history_retain: Option<Duration>,
 }
 
-fn main() {
-    let mut args = Args::parse();
+impl HandlerArgs {
+    /// Writer of the records, and handler running the command for an event
+    ///
+    /// Without --args, the handler writes the kind and path of the event as a record.
+    fn handler(
+        self,
+    ) -> (
+        LogWriter,
+        impl Fn(&EventRecord) -> Result<(), String> + Send + Sync + 'static,
+    ) {
+        let mut args = self;
 
-    let path = Path::new(&args.path);
+        // Validate the shell command
+        let cmd_required = args.sh_cmd.is_some();
+        if cmd_required && args.args.is_none() {
+            panic!("Arguments are required when --sh-cmd is provided");
+        }
 
-    match path {
-        _ if path.exists() == false => {
-            panic!("Path does not exist");
+        if !cmd_required {
+            // If the shell command is not provided, then set the default shell command based on the OS
+            args.sh_cmd = if cfg!(target_os = "windows") {
+                Some("cmd /C".to_string())
+            } else {
+                Some("sh -c".to_string())
+            };
         }
-        _ if path.is_dir() == false => {
-            panic!("Path is not a directory");
+        let sh_cmd_split: Vec<String> = args
+            .sh_cmd
+            .unwrap()
+            .trim()
+            .split(" ")
+            .map(|s| s.to_string())
+            .collect();
+        if sh_cmd_split.is_empty() {
+            panic!("Invalid shell command, should be in the format: <shell> <command> for example: /bin/bash -c");
         }
-        _ => (),
-    }
 
-    // Validate the shell command
-    let cmd_required = args.sh_cmd.is_some();
-    if cmd_required && args.args.is_none() {
-        panic!("Arguments are required when --sh-cmd is provided");
-    }
+        let rotation = RotationOptions {
+            max_size: args.rotate_size,
+            every: args.rotate_every,
+            retain: args.retain,
+            compress: args.compress,
+        };
 
-    if cmd_required == false {
-        // If the shell command is not provided, then set the default shell command based on the OS
-        args.sh_cmd = if cfg!(target_os = "windows") {
-            Some("cmd /C".to_string())
+        // Dry-run records stay local, they are not sent to syslog, the journal or the webhook
+        let outputs: Vec<&String> = args
+            .output
+            .iter()
+            .filter(|output| {
+                let skipped = args.dry_run && is_log_service(output);
+                if skipped {
+                    warn!(output = %output, "Output ignored with --dry-run");
+                }
+                !skipped
+            })
+            .collect();
+
+        // Records are written by a dedicated thread, the output files stay open across writes
+        let mut sinks: Vec<Sink> = if outputs.is_empty() {
+            vec![Sink::Stdout]
         } else {
-            Some("sh -c".to_string())
+            outputs
+                .into_iter()
+                .map(|output| {
+                    open_sink(output, rotation, args.syslog_facility)
+                        .unwrap_or_else(|e| panic!("Unable to open output '{}': {}", output, e))
+                })
+                .collect()
         };
+        if let Some(url) = args.webhook.as_ref().filter(|_| !args.dry_run) {
+            let options = WebhookOptions {
+                headers: args.webhook_header.clone(),
+                secret: args.webhook_secret.clone(),
+                batch_size: args.webhook_batch,
+                timeout: Duration::from_millis(args.webhook_timeout),
+                max_retries: args.webhook_retries,
+                spool_dir: args.webhook_spool.clone(),
+                ..WebhookOptions::default()
+            };
+            sinks.push(Sink::Webhook(
+                Webhook::spawn(url, options).expect("Unable to start the webhook"),
+            ));
+        }
+        let log_writer = LogWriter::spawn(
+            sinks,
+            WriterOptions {
+                sync_interval: match args.sync_interval {
+                    0 => None,
+                    interval => Some(Duration::from_millis(interval)),
+                },
+                ..WriterOptions::default()
+            },
+        );
+        let record_writer = log_writer.clone();
+        let dry_run = args
+            .dry_run
+            .then(|| DryRun::new(&args.args.as_deref().unwrap().join(" ")));
+
+        #[cfg(feature = "history")]
+        let history = args.history.as_deref().map(|path| {
+            let history = History::open(path).expect("Unable to open the history database");
+            match args.history_retain {
+                Some(age) => history.retain(age),
+                None => history,
+            }
+        });
+
+        // Closure to handle the events
+        let f = move |record: &EventRecord| -> Result<(), String> {
+            // Get the path of the file that triggered the event
+            let path = record
+                .paths
+                .first()
+                .map(|path| path.to_string_lossy())
+                .unwrap_or_default();
+            let clean_path = if cfg!(target_os = "windows") {
+                path.replace(r"\\?\", "")
+            } else {
+                path.to_string()
+            };
+            let kind = record.detail.as_str();
+
+            // Nothing is run nor recorded in the history
+            if let Some(dry_run) = &dry_run {
+                let parsed_args = parse_command(args.args.as_deref().unwrap(), &clean_path, kind);
+                record_writer.write(dry_run.command_record(
+                    record,
+                    &sh_cmd_split,
+                    &parsed_args.join(" "),
+                ));
+                return Ok(());
+            }
+
+            #[cfg(feature = "history")]
+            let mut entry = Entry::new(record);
+
+            // By default just prints the event kind and path of the file that triggered the event
+            let result = if !cmd_required && args.args.is_none() {
+                record_writer.write(json_record(&[("Kind", kind), ("Path", &clean_path)]));
+                Ok(())
+                // If args are provided, then parse the command and execute it
+            } else {
+                let parsed_args =
+                    parse_command(args.args.clone().unwrap().as_ref(), &clean_path, kind);
+
+                // Execute the command and print the stdout and stderr
+                let args_str = parsed_args.join(" ");
+                debug!(command = %args_str, "Command spawned");
+                #[cfg(feature = "history")]
+                {
+                    entry.command = Some(args_str.clone());
+                }
+                let started = Instant::now();
+                let child: Child = command_exec(&sh_cmd_split, args_str);
+
+                if let Ok(output) = child.wait_with_output() {
+                    debug!(status = %output.status, elapsed = ?started.elapsed(), "Command finished");
+                    #[cfg(feature = "history")]
+                    {
+                        entry.exit_code = output.status.code();
+                        entry.duration = Some(started.elapsed());
+                    }
+                    let cmd_stdout = String::from_utf8_lossy(&output.stdout);
+                    let cmd_stderr = String::from_utf8_lossy(&output.stderr);
+
+                    record_writer.write(json_record(&[
+                        ("stdout", cmd_stdout.trim()),
+                        ("stderr", cmd_stderr.trim()),
+                    ]));
+
+                    if output.status.success() {
+                        Ok(())
+                    } else {
+                        Err(format!("Command exited with {}", output.status))
+                    }
+                } else {
+                    Err(String::from("Command terminated unexpectedly."))
+                }
+            };
+
+            #[cfg(feature = "history")]
+            if let Some(history) = &history {
+                if let Err(e) = history.record(&entry) {
+                    error!(error = %e, "Couldn't record the event in the history");
+                }
+            }
+            result
+        };
+        (log_writer, f)
     }
-    let sh_cmd_split: Vec<String> = args
-        .sh_cmd
-        .unwrap()
-        .trim()
-        .split(" ")
-        .map(|s| s.to_string())
-        .collect();
-    if sh_cmd_split.len() < 1 {
-        panic!("Invalid shell command, should be in the format: <shell> <command> for example: /bin/bash -c");
+}
+
+/// Log a failed command, a warning as watchcrab keeps running unless --max-failures is reached
+fn warn_failure(event: &Event, failure: &HandlerFailure) {
+    match event.paths.first() {
+        Some(path) => warn!(path = %path.display(), %failure, "Command failed"),
+        None => warn!(%failure, "Command failed"),
     }
+}
 
-    // Select the write to log file function based on the threads flag
-    let write_to_log = if args.threads > 1 {
-        write_to_log_file_async
-    } else {
-        write_to_log_file
-    };
+/// Target of the --explain logs, apart from the diagnostics of watchcrab
+const EXPLAIN_TARGET: &str = "watchcrab::explain";
 
-    // Check if the output file is required and create it if it does not exist
-    let mut output_file_path = PathBuf::new();
-    let mut output_file_required = false;
-    if args.output.is_some() {
-        output_file_path = PathBuf::from(args.output.unwrap().as_str());
+/// Log a decision taken on an event for --explain, with the explain target so --log-level applies to it
+fn explain(event: &Event, decision: &Decision) {
+    let record = EventRecord::new(event);
+    info!(
+        target: EXPLAIN_TARGET,
+        kind = %record.kind,
+        detail = %record.detail,
+        paths = ?record.paths,
+        "{}",
+        decision
+    );
+}
 
-        // Create the file if it does not exist
-        if output_file_path.exists() == false {
-            std::fs::write(&output_file_path, "").expect("Unable to create log file");
+/// Records written by --dry-run, the commands would run in the working directory and with the environment of watchcrab
+struct DryRun {
+    cwd: PathBuf,
+    env: BTreeMap<String, String>,
+}
+
+impl DryRun {
+    /// Only the variables referenced by the command are recorded, the rest of the environment may hold secrets
+    fn new(command: &str) -> DryRun {
+        DryRun {
+            cwd: std::env::current_dir().unwrap_or_default(),
+            env: referenced_vars(command)
+                .into_iter()
+                .filter_map(|name| {
+                    let value = std::env::var_os(&name)?;
+                    Some((name, value.to_string_lossy().to_string()))
+                })
+                .collect(),
         }
+    }
 
-        output_file_path = output_file_path.canonicalize().unwrap(); // Get the absolute path
+    /// Record of the command that would run for an event
+    fn command_record(&self, record: &EventRecord, shell: &[String], command: &str) -> String {
+        serde_json::json!({
+            "dry_run": "run",
+            "kind": record.kind,
+            "detail": record.detail,
+            "paths": record.paths,
+            "shell": shell,
+            "command": command,
+            "cwd": self.cwd,
+            "env": self.env,
+        })
+        .to_string()
+    }
 
-        output_file_required = true;
+    /// Callback writing a record for each skipped event
+    fn on_skipped(log_writer: LogWriter) -> SkipCallback {
+        Arc::new(move |event: &Event, reason: SkipReason| {
+            let record = EventRecord::new(event);
+            let skipped = serde_json::json!({
+                "dry_run": "skip",
+                "kind": record.kind,
+                "detail": record.detail,
+                "paths": record.paths,
+                "reason": reason.to_string(),
+            });
+            log_writer.write(skipped.to_string());
+        })
     }
+}
 
-    // Closure to handle the events
-    let f = Arc::new(Box::new(move |event: Event| {
-        // Get the path of the file that triggered the event
-        let path = event.paths.iter().next().unwrap().to_str().unwrap();
-        let clean_path = if cfg!(target_os = "windows") {
-            path.replace(r"\\?\", "")
-        } else {
-            path.to_string()
+/// Names of the environment variables a command references, as $NAME or ${NAME}, and %NAME% on Windows
+fn referenced_vars(command: &str) -> BTreeSet<String> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut names = BTreeSet::new();
+    let mut rest = command;
+    while let Some(start) = rest.find(['$', '%']) {
+        let sigil = &rest[start..start + 1];
+        rest = &rest[start + 1..];
+        let (name, end) = match (sigil, rest.strip_prefix('{')) {
+            ("$", Some(braced)) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => continue,
+            },
+            ("$", None) => {
+                let end = rest.find(|c| !is_name(c)).unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+            _ if cfg!(windows) => match rest.find('%') {
+                Some(end) if rest[..end].chars().all(is_name) => (&rest[..end], end + 1),
+                _ => continue,
+            },
+            _ => continue,
         };
+        if !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) {
+            names.insert(name.to_string());
+        }
+        rest = &rest[end..];
+    }
+    names
+}
 
-        // By default just prints the event kind and path of the file that triggered the event
-        if !cmd_required && args.args.is_none() {
-            let json_output = format!(
-                r#"{{"Kind": "{}", "Path": "{}"}}"#,
-                format!("{:?}", event.kind).as_str(),
-                clean_path
-            );
-            if output_file_required {
-                write_to_log(&output_file_path, &json_output);
-            } else {
-                println!("{}", json_output);
+/// Whether an --output value is syslog or the systemd journal
+fn is_log_service(output: &str) -> bool {
+    matches!(output.split(':').next(), Some("syslog" | "journald"))
+}
+
+/// Sink of an --output value
+fn open_sink(output: &str, rotation: RotationOptions, facility: Facility) -> Result<Sink, Error> {
+    let syslog_options = || SyslogOptions {
+        facility,
+        ..SyslogOptions::default()
+    };
+    match output {
+        "-" => Ok(Sink::Stdout),
+        "syslog" => Ok(Sink::Syslog(Syslog::connect(
+            &SyslogAddr::default(),
+            syslog_options(),
+        )?)),
+        "journald" => Ok(Sink::Journald(Journald::connect(
+            Path::new(journald::DEFAULT_SOCKET),
+            "watchcrab",
+        )?)),
+        _ => match output.split_once(':') {
+            Some(("syslog", addr)) => {
+                let addr: SyslogAddr = addr
+                    .parse()
+                    .map_err(|e: String| Error::new(ErrorKind::InvalidInput, e))?;
+                Ok(Sink::Syslog(Syslog::connect(&addr, syslog_options())?))
             }
-            // If args are provided, then parse the command and execute it
-        } else {
-            let parsed_args = parse_command(
-                args.args.clone().unwrap().as_ref(),
-                &clean_path,
-                &format!("{:?}", event.kind).as_str(),
-            );
-
-            // Execute the command and print the stdout and stderr
-            let args_str = parsed_args.join(" ");
-            let child: Child = command_exec(&sh_cmd_split, args_str);
-
-            if let Ok(output) = child.wait_with_output() {
-                let cmd_stdout = String::from_utf8_lossy(&output.stdout);
-                let cmd_stderr = String::from_utf8_lossy(&output.stderr);
-
-                let json_output = format!(
-                    r#"{{"stdout": "{}", "stderr": "{}"}}"#,
-                    cmd_stdout.trim(),
-                    cmd_stderr.trim()
-                );
+            Some(("journald", path)) => Ok(Sink::Journald(Journald::connect(
+                Path::new(path),
+                "watchcrab",
+            )?)),
+            _ => Sink::file(Path::new(output), rotation),
+        },
+    }
+}
 
-                if output_file_required {
-                    write_to_log(&output_file_path, &json_output);
-                } else {
-                    println!("{}", json_output);
-                }
-            } else {
-                eprintln!("Command terminated unexpectedly.");
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    #[command(flatten)]
+    watch: WatchArgs,
+
+    /// Address to listen on: "unix:<path>", "tcp:<host>:<port>" or "<host>:<port>", can be repeated
+    #[arg(short, long, required = true)]
+    listen: Vec<ServerAddr>,
+
+    /// Number of events waiting to be sent to a client before it is considered too slow
+    #[arg(long, default_value_t = DEFAULT_CLIENT_BUFFER)]
+    client_buffer: usize,
+
+    /// What to do with a client too slow to read its events: "disconnect" or "drop" (skip events until it catches up)
+    #[arg(long, default_value_t = SlowClientPolicy::Disconnect)]
+    slow_client: SlowClientPolicy,
+}
+
+#[derive(clap::Args, Debug)]
+struct SubscribeArgs {
+    /// Address of the server: "unix:<path>", "tcp:<host>:<port>" or "<host>:<port>"
+    #[arg(short, long)]
+    connect: ServerAddr,
+
+    /// Glob pattern of the paths to receive, can be repeated, by default every path. Patterns containing "/" are relative to the watched directory
+    #[arg(short, long)]
+    include: Vec<String>,
+
+    /// Glob pattern of the paths to skip, can be repeated
+    #[arg(short = 'x', long)]
+    exclude: Vec<String>,
+
+    /// Events to receive, by default does not filter any events
+    #[arg(short = 'e', long, num_args = 1.., value_delimiter = ' ', default_values = &["all"])]
+    events: Vec<String>,
+
+    #[command(flatten)]
+    handler: HandlerArgs,
+
+    /// Number of threads to execute the command in, by default it will execute the command in the main thread
+    #[arg(short = 't', long, default_value_t = 1)]
+    threads: usize,
+
+    /// With --threads, run the commands of events sharing a key one at a time and in order: "none", "path" (same file) or "parent" (same directory)
+    #[arg(long, default_value_t = SerializeBy::None)]
+    serialize_by: SerializeBy,
+
+    /// Reconnect after this number of milliseconds when the connection to the server is lost, by default watchcrab stops
+    #[arg(long)]
+    reconnect: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RecordArgs {
+    #[command(flatten)]
+    watch: WatchArgs,
+
+    /// Session file to write, one JSON line per event, replaced if it exists
+    #[arg(long)]
+    to: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// Session file written by the record subcommand
+    session: PathBuf,
+
+    /// Replay this number of times faster than recorded, 0 to replay the events without waiting
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+
+    /// Events to replay, by default does not filter any events
+    #[arg(short = 'e', long, num_args = 1.., value_delimiter = ' ', default_values = &["all"])]
+    events: Vec<String>,
+
+    #[command(flatten)]
+    handler: HandlerArgs,
+
+    #[command(flatten)]
+    pipeline: PipelineArgs,
+}
+
+#[cfg(feature = "history")]
+#[derive(clap::Args, Debug)]
+struct HistoryArgs {
+    /// History database, written with --history
+    db: PathBuf,
+
+    /// Only list the events since this time: a duration ago like "12h" or a UTC date like "2024-05-01T22:00:00Z"
+    #[arg(long, value_parser = |time: &str| parse_time(time, SystemTime::now()))]
+    since: Option<SystemTime>,
+
+    /// Only list the events before this time, in the same format as --since
+    #[arg(long, value_parser = |time: &str| parse_time(time, SystemTime::now()))]
+    until: Option<SystemTime>,
+
+    /// Only list the events of the paths matching this glob pattern, patterns containing "/" are matched against the absolute path
+    #[arg(short, long)]
+    include: Option<String>,
+
+    /// Events to list, by default does not filter any events
+    #[arg(short = 'e', long, num_args = 1.., value_delimiter = ' ', default_values = &["all"])]
+    events: Vec<String>,
+
+    /// List at most this number of events, the most recent ones
+    #[arg(short = 'n', long)]
+    limit: Option<usize>,
+
+    /// Delete the events older than this age, like "30d", instead of listing events
+    #[arg(long, value_parser = parse_duration, conflicts_with_all = ["since", "until", "include", "limit"])]
+    prune: Option<Duration>,
+}
+
+fn main() {
+    let mut args = Args::parse();
+
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_max_level(args.log_level)
+        .init();
+
+    let result = match args.command.take() {
+        Some(Command::Serve(serve_args)) => serve(serve_args),
+        Some(Command::Subscribe(subscribe_args)) => subscribe(*subscribe_args),
+        Some(Command::Record(record_args)) => record(record_args),
+        Some(Command::Replay(replay_args)) => replay(*replay_args),
+        #[cfg(feature = "history")]
+        Some(Command::History(history_args)) => {
+            // Listing the history doesn't watch anything, there is nothing to report when it ends
+            if let Err(e) = history(history_args) {
+                error!("WatchCrab Error: {}", e);
+                std::process::exit(1);
             }
+            return;
         }
-    }) as Box<dyn Fn(Event) + Send + Sync + 'static>);
-
-    let watchcrab_watch = Watch::new(&path, args.recursive, &args.events, f, args.threads);
-    let result = watchcrab_watch.start();
+        None => run(args),
+    };
 
     match result {
         Ok(_) => {
-            println!("WatchCrab stopped successfully. All tasks have completed.");
+            info!("WatchCrab stopped successfully. All tasks have completed.");
             std::process::exit(0);
         }
         Err(e) => {
-            eprintln!("WatchCrab Error: {}", e);
+            error!("WatchCrab Error: {}", e);
             std::process::exit(1);
         }
     }
 }
+
+/// Broadcast the events to the connected clients
+fn serve(args: ServeArgs) -> Result<(), Error> {
+    let options = ServerOptions {
+        client_buffer: args.client_buffer,
+        slow_client: args.slow_client,
+    };
+    let server = Server::bind(&args.listen, Path::new(&args.watch.path), options)?;
+
+    // A single thread keeps the events in order, broadcasting never waits for the clients
+    let f = move |event: Event| -> Result<(), HandlerError> {
+        server.broadcast(&event);
+        Ok(())
+    };
+    args.watch.watch(f, 1).start()
+}
+
+/// Run the command, or write a record, for each event received from a server
+fn subscribe(args: SubscribeArgs) -> Result<(), Error> {
+    let subscription = Subscription {
+        include: args.include,
+        exclude: args.exclude,
+        kinds: if args.events == ["all"] {
+            Vec::new()
+        } else {
+            args.events
+        },
+    };
+    let (log_writer, handle) = args.handler.handler();
+    let handle = Arc::new(handle);
+    let executor = KeyedExecutor::new(args.threads, args.serialize_by);
+    // The commands are run like the ones of a watch: a failure or a panic is counted and logged, never propagated
+    let accounting = Arc::new(Accounting::new(
+        Arc::new(warn_failure),
+        Arc::new(HandlerStats::default()),
+    ));
+    let signal = TerminationSignal::spawn()?;
+
+    // The connection is read in its own thread so a termination signal is handled while waiting for events
+    let (record_tx, record_rx) = bounded(args.threads.max(1));
+    let addr = args.connect;
+    let reconnect = args.reconnect.map(Duration::from_millis);
+    let reader = thread::spawn(move || -> Result<(), Error> {
+        loop {
+            let result = Subscriber::connect(&addr, &subscription).and_then(|subscriber| {
+                info!(address = %addr, "Connected to the server");
+                for record in subscriber {
+                    if record_tx.send(record?).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+            match (result, reconnect) {
+                // The server rejected the subscription, reconnecting won't help
+                (Err(e), _) if e.kind() == ErrorKind::InvalidInput => return Err(e),
+                (result, Some(delay)) => {
+                    match result {
+                        Ok(_) => {
+                            warn!(address = %addr, "Connection closed by the server, reconnecting")
+                        }
+                        Err(e) => {
+                            warn!(address = %addr, error = %e, "Connection to the server failed, reconnecting")
+                        }
+                    }
+                    thread::sleep(delay);
+                }
+                (result, None) => return result,
+            }
+        }
+    });
+
+    let interrupted = loop {
+        select! {
+            recv(record_rx) -> record => {
+                let Ok(record) = record else {
+                    break false;
+                };
+                let Some(path) = record.paths.first() else {
+                    continue;
+                };
+                // Events are serialized by the path of the record
+                let event = Event::new(EventKind::Any).add_path(path.clone());
+                let handle = Arc::clone(&handle);
+                let accounting = Arc::clone(&accounting);
+                executor.execute(&event.clone(), move || {
+                    let _ = accounting.run(&event, || handle(&record).map_err(HandlerError::from));
+                });
+            }
+            recv(signal.rx) -> _ => {
+                info!("Termination signal received. Waiting for ongoing tasks to complete...");
+                break true;
+            }
+        }
+    };
+
+    executor.join();
+    log_writer.flush(); // Write the last records before exiting
+    signal.close();
+    if interrupted {
+        // The reader is blocked on the connection, it stops with the process
+        drop(reader);
+        Ok(())
+    } else {
+        // The reader dropped the records channel when it returned
+        reader.join().unwrap()
+    }
+}
+
+/// Relay of SIGINT and SIGTERM to a channel, until it is closed
+struct TerminationSignal {
+    rx: Receiver<()>,
+    #[cfg(target_family = "unix")]
+    handle: signal_hook::iterator::Handle,
+    #[cfg(target_family = "unix")]
+    thread: thread::JoinHandle<()>,
+}
+
+impl TerminationSignal {
+    #[cfg(target_family = "unix")]
+    fn spawn() -> Result<TerminationSignal, Error> {
+        let (signal_tx, rx) = bounded(1);
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let handle = signals.handle();
+        let thread = thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                let _ = signal_tx.send(());
+            }
+        });
+        Ok(TerminationSignal { rx, handle, thread })
+    }
+
+    /// Ctrl+C stops the process right away on Windows
+    #[cfg(target_family = "windows")]
+    fn spawn() -> Result<TerminationSignal, Error> {
+        Ok(TerminationSignal {
+            rx: crossbeam_channel::never(),
+        })
+    }
+
+    /// Stop the relay thread and wait for it
+    fn close(self) {
+        #[cfg(target_family = "unix")]
+        {
+            self.handle.close();
+            let _ = self.thread.join();
+        }
+    }
+}
+
+/// List the events of the history as JSON lines, or prune them
+#[cfg(feature = "history")]
+fn history(args: HistoryArgs) -> Result<(), Error> {
+    let history = History::open(&args.db)?;
+    if let Some(age) = args.prune {
+        let pruned = history.prune(SystemTime::now() - age)?;
+        info!(pruned, "History pruned");
+        return Ok(());
+    }
+
+    let query = Query {
+        since: args.since,
+        until: args.until,
+        glob: args.include,
+        kinds: if args.events == ["all"] {
+            Vec::new()
+        } else {
+            args.events
+        },
+        limit: args.limit,
+    };
+    let mut stdout = std::io::stdout().lock();
+    for entry in history.query(&query)? {
+        let line = serde_json::json!({
+            "time": format_timestamp(entry.time),
+            "kind": entry.kind,
+            "detail": entry.detail,
+            "paths": entry.paths,
+            "command": entry.command,
+            "exit_code": entry.exit_code,
+            "duration_ms": entry.duration.map(|duration| duration.as_millis() as u64),
+        });
+        writeln!(stdout, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Run the command, or write a record, for each event
+fn run(args: Args) -> Result<(), Error> {
+    let dry_run = args.handler.dry_run;
+    let (log_writer, handle) = args.handler.handler();
+    let on_skipped = dry_run.then(|| DryRun::on_skipped(log_writer.clone()));
+
+    let f = move |event: Event| -> Result<(), String> { handle(&EventRecord::new(&event)) };
+
+    let watchcrab_watch = args.watch.watch(f, args.pipeline.threads);
+    let result = args
+        .pipeline
+        .apply(watchcrab_watch, 1.0, on_skipped)
+        .start();
+    log_writer.flush(); // Write the last records before exiting
+    result
+}
+
+/// Write the events to a session file with their timings
+fn record(args: RecordArgs) -> Result<(), Error> {
+    let recorder = Recorder::create(&args.to)?;
+    info!(session = %args.to.display(), "Recording");
+
+    // A single thread keeps the events in the order they were received
+    let f = move |event: Event| recorder.record(&event);
+    args.watch.watch(f, 1).start()
+}
+
+/// Run the command, or write a record, for each event of a session file
+fn replay(args: ReplayArgs) -> Result<(), Error> {
+    let session = Replay::open(&args.session)?.speed(args.speed);
+    let dry_run = args.handler.dry_run;
+    let (log_writer, handle) = args.handler.handler();
+    let on_skipped = dry_run.then(|| DryRun::on_skipped(log_writer.clone()));
+
+    let f = move |event: Event| -> Result<(), String> { handle(&EventRecord::new(&event)) };
+
+    // No watcher is registered, the path only satisfies the constructor
+    let watchcrab_watch = Watch::new_fallible(
+        Path::new("."),
+        false,
+        &args.events,
+        f,
+        args.pipeline.threads,
+    );
+    // Without waiting between the events, the layers keep their timings
+    let time_scale = if args.speed > 0.0 { args.speed } else { 1.0 };
+    let result = args
+        .pipeline
+        .apply(watchcrab_watch, time_scale, on_skipped)
+        .replay(session);
+    log_writer.flush(); // Write the last records before exiting
+    result
+}
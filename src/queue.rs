@@ -0,0 +1,385 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TrySendError};
+use notify::Event;
+
+/// Capacity of the event queue of the command line tool, the library queue is unbounded by default
+pub const DEFAULT_QUEUE_CAPACITY: usize = 4096;
+
+/// What to do with an event when the event queue is full
+///
+/// * `Block` - Wait until the queue has room, the backend stops reading events (the kernel queue may overflow)
+/// * `DropOldest` - Drop the oldest queued event to make room for the new one
+/// * `DropNewest` - Drop the new event
+/// * `Coalesce` - Keep the events waiting for room apart and merge them by path: a creation absorbs the later modifications,
+///   otherwise the latest event of a path is kept. The oldest waiting event is dropped once as many events wait as the queue holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    #[default]
+    Block,
+    DropOldest,
+    DropNewest,
+    Coalesce,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            _ => Err(format!(
+                "Invalid overflow policy '{}', expected one of: block, drop-oldest, drop-newest, coalesce",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverflowPolicy::Block => write!(f, "block"),
+            OverflowPolicy::DropOldest => write!(f, "drop-oldest"),
+            OverflowPolicy::DropNewest => write!(f, "drop-newest"),
+            OverflowPolicy::Coalesce => write!(f, "coalesce"),
+        }
+    }
+}
+
+/// Counters of the events lost or merged because the event queue was full
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+impl QueueStats {
+    /// Number of events dropped since the watcher started
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of events merged with another event of the same path since the watcher started
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+/// Sending side of the event queue, applies the overflow policy when the queue is full
+///
+/// Any number of senders can be cloned from the queue, one per watcher.
+#[derive(Clone)]
+pub struct QueueSender {
+    tx: Sender<Result<Event, notify::Error>>,
+    // Receiving side used to drop the oldest queued events, crossbeam channels are multi-consumer.
+    // Only kept by the policies that never block, so a blocked sender can't keep the channel alive.
+    rx: Option<Receiver<Result<Event, notify::Error>>>,
+    // Events waiting for room with the `Coalesce` policy, and the wake up of the thread moving them to the queue
+    overflow: Option<(Arc<Overflow>, Sender<()>)>,
+    policy: OverflowPolicy,
+    stats: Arc<QueueStats>,
+}
+
+/// Events waiting for room in a full queue with the `Coalesce` policy
+///
+/// They are merged under the lock, out of reach of the consumer, and moved to the queue in order by a dedicated thread.
+struct Overflow {
+    state: Mutex<OverflowState>,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct OverflowState {
+    events: VecDeque<Result<Event, notify::Error>>,
+    // An event was taken by the thread and is waiting for room, the senders must queue behind it
+    forwarding: bool,
+}
+
+/// Create an event queue
+///
+/// # Arguments
+/// * `capacity` - Maximum number of queued events, 0 for an unbounded queue
+/// * `policy` - What to do with an event when the queue is full
+pub fn event_queue(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (QueueSender, Receiver<Result<Event, notify::Error>>) {
+    let (tx, rx) = if capacity == 0 {
+        unbounded()
+    } else {
+        bounded(capacity)
+    };
+    let overflow = (policy == OverflowPolicy::Coalesce && capacity > 0).then(|| {
+        let overflow = Arc::new(Overflow {
+            state: Mutex::new(OverflowState::default()),
+            capacity,
+        });
+        let (wake_tx, wake_rx) = bounded(1);
+        let forwarded = Arc::clone(&overflow);
+        let forward_tx = tx.clone();
+        thread::spawn(move || forward(&forwarded, &forward_tx, &wake_rx));
+        (overflow, wake_tx)
+    });
+    let sender = QueueSender {
+        tx,
+        rx: (policy == OverflowPolicy::DropOldest).then(|| rx.clone()),
+        overflow,
+        policy,
+        stats: Arc::new(QueueStats::default()),
+    };
+    (sender, rx)
+}
+
+/// Move the overflow events to the queue in order, until every sender or the receiver is dropped
+fn forward(overflow: &Overflow, tx: &Sender<Result<Event, notify::Error>>, wake: &Receiver<()>) {
+    loop {
+        let next = {
+            let mut state = overflow.state.lock().unwrap();
+            let next = state.events.pop_front();
+            state.forwarding = next.is_some();
+            next
+        };
+        match next {
+            Some(event) => {
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+            // Every event pushed before the last sender was dropped woke the thread up
+            None => {
+                if wake.recv().is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl QueueSender {
+    /// Counters shared by every sender of the queue
+    pub fn stats(&self) -> Arc<QueueStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Queue an event, applying the overflow policy if the queue is full
+    ///
+    /// # Returns
+    /// `false` if the receiving side was dropped
+    pub fn send(&self, event: Result<Event, notify::Error>) -> bool {
+        if let Some((overflow, wake)) = &self.overflow {
+            return self.send_coalescing(overflow, wake, event);
+        }
+        let event = match self.tx.try_send(event) {
+            Ok(()) => return true,
+            Err(TrySendError::Disconnected(_)) => return false,
+            Err(TrySendError::Full(event)) => event,
+        };
+
+        match self.policy {
+            OverflowPolicy::Block => self.tx.send(event).is_ok(),
+            OverflowPolicy::DropNewest => {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            // Without overflow, the queue is unbounded and never full
+            OverflowPolicy::DropOldest | OverflowPolicy::Coalesce => {
+                self.send_dropping_oldest(event)
+            }
+        }
+    }
+
+    /// Queue an event, dropping the oldest queued events until there is room for it
+    fn send_dropping_oldest(&self, mut event: Result<Event, notify::Error>) -> bool {
+        let Some(rx) = &self.rx else {
+            return self.tx.try_send(event).is_ok();
+        };
+        loop {
+            match self.tx.try_send(event) {
+                Ok(()) => return true,
+                Err(TrySendError::Disconnected(_)) => return false,
+                Err(TrySendError::Full(full)) => {
+                    event = full;
+                    if rx.try_recv().is_ok() {
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queue an event, or merge it with the overflow events when the queue is full or events are waiting for room
+    fn send_coalescing(
+        &self,
+        overflow: &Overflow,
+        wake: &Sender<()>,
+        event: Result<Event, notify::Error>,
+    ) -> bool {
+        let mut state = overflow.state.lock().unwrap();
+        let event = if state.events.is_empty() && !state.forwarding {
+            match self.tx.try_send(event) {
+                Ok(()) => return true,
+                Err(TrySendError::Disconnected(_)) => return false,
+                Err(TrySendError::Full(event)) => event,
+            }
+        } else {
+            event
+        };
+
+        let waiting = match &event {
+            Ok(event) if !event.paths.is_empty() && !event.need_rescan() => {
+                state.events.iter_mut().find_map(|waiting| match waiting {
+                    Ok(waiting) if waiting.paths == event.paths && !waiting.need_rescan() => {
+                        Some(waiting)
+                    }
+                    _ => None,
+                })
+            }
+            _ => None,
+        };
+        match (waiting, event) {
+            (Some(waiting), Ok(event)) => {
+                merge(waiting, event);
+                self.stats.coalesced.fetch_add(1, Ordering::Relaxed);
+            }
+            (_, event) => {
+                state.events.push_back(event);
+                if state.events.len() > overflow.capacity {
+                    state.events.pop_front();
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        drop(state);
+        let _ = wake.try_send(());
+        true
+    }
+}
+
+/// Merge an event into an earlier event of the same paths: a creation absorbs the modifications, otherwise the latest
+/// event is kept
+fn merge(waiting: &mut Event, event: Event) {
+    if !(waiting.kind.is_create() && event.kind.is_modify()) {
+        *waiting = event;
+    }
+}
+
+impl notify::EventHandler for QueueSender {
+    fn handle_event(&mut self, event: Result<Event, notify::Error>) {
+        self.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, ModifyKind, RemoveKind};
+    use notify::EventKind;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    fn event(kind: EventKind, path: &str) -> Result<Event, notify::Error> {
+        Ok(Event::new(kind).add_path(PathBuf::from(path)))
+    }
+
+    fn paths(rx: &Receiver<Result<Event, notify::Error>>) -> Vec<PathBuf> {
+        rx.try_iter().map(|e| e.unwrap().paths[0].clone()).collect()
+    }
+
+    #[test]
+    fn test_drop_policies() {
+        let (tx, rx) = event_queue(2, OverflowPolicy::DropNewest);
+        for path in ["/a", "/b", "/c"] {
+            tx.send(event(EventKind::Any, path));
+        }
+        assert_eq!(paths(&rx), vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+        assert_eq!(tx.stats().dropped(), 1);
+
+        let (tx, rx) = event_queue(2, OverflowPolicy::DropOldest);
+        for path in ["/a", "/b", "/c"] {
+            tx.send(event(EventKind::Any, path));
+        }
+        assert_eq!(paths(&rx), vec![PathBuf::from("/b"), PathBuf::from("/c")]);
+        assert_eq!(tx.stats().dropped(), 1);
+    }
+
+    /// Wait until the forwarding thread took the overflow events and waits for room
+    fn wait_forwarding(tx: &QueueSender) {
+        let (overflow, _) = tx.overflow.as_ref().unwrap();
+        loop {
+            let state = overflow.state.lock().unwrap();
+            if state.events.is_empty() && state.forwarding {
+                return;
+            }
+            drop(state);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_coalesce_merges_the_events_waiting_for_room() {
+        let (tx, rx) = event_queue(2, OverflowPolicy::Coalesce);
+        tx.send(event(EventKind::Create(CreateKind::File), "/a"));
+        tx.send(event(EventKind::Any, "/x"));
+        tx.send(event(EventKind::Any, "/y"));
+        wait_forwarding(&tx);
+
+        tx.send(event(EventKind::Create(CreateKind::File), "/b"));
+        tx.send(event(EventKind::Modify(ModifyKind::Any), "/b"));
+        tx.send(event(EventKind::Modify(ModifyKind::Any), "/a"));
+        tx.send(event(EventKind::Remove(RemoveKind::File), "/a"));
+
+        let events: Vec<Event> = (0..5)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap())
+            .collect();
+        let kinds: Vec<(EventKind, &Path)> = events
+            .iter()
+            .map(|event| (event.kind, event.paths[0].as_path()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (EventKind::Create(CreateKind::File), Path::new("/a")),
+                (EventKind::Any, Path::new("/x")),
+                (EventKind::Any, Path::new("/y")),
+                // The creation of /b absorbed its modification, the removal of /a replaced its modification
+                (EventKind::Create(CreateKind::File), Path::new("/b")),
+                (EventKind::Remove(RemoveKind::File), Path::new("/a")),
+            ]
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(tx.stats().coalesced(), 2);
+        assert_eq!(tx.stats().dropped(), 0);
+    }
+
+    #[test]
+    fn test_coalesce_drops_the_oldest_waiting_event() {
+        let (tx, rx) = event_queue(1, OverflowPolicy::Coalesce);
+        tx.send(event(EventKind::Any, "/a"));
+        tx.send(event(EventKind::Any, "/x"));
+        wait_forwarding(&tx);
+
+        tx.send(event(EventKind::Any, "/b"));
+        tx.send(event(EventKind::Any, "/c"));
+        assert_eq!(tx.stats().dropped(), 1);
+        drop(tx);
+
+        let events: Vec<PathBuf> = rx.iter().map(|e| e.unwrap().paths[0].clone()).collect();
+        assert_eq!(
+            events,
+            vec![
+                PathBuf::from("/a"),
+                PathBuf::from("/x"),
+                PathBuf::from("/c")
+            ]
+        );
+    }
+}
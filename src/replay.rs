@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Lines, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use notify::Event;
+use serde::{Deserialize, Serialize};
+
+/// Line of a session file: an event and the time elapsed since the start of the recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    pub elapsed_ms: u64,
+    pub event: Event,
+}
+
+/// Writes the events to a session file, to replay them later with `Replay`
+///
+/// The session file has one JSON `RecordedEvent` per line, each line is flushed so a session
+/// interrupted by a crash can still be replayed.
+///
+/// ```text
+/// {"elapsed_ms":120,"event":{"type":{"create":{"kind":"file"}},"paths":["/tmp/dir/new.txt"],"attrs":{}}}
+/// ```
+pub struct Recorder {
+    writer: Mutex<BufWriter<File>>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Create the session file, replacing an existing one, the timings are relative to now
+    ///
+    /// # Errors
+    /// Returns an `Error` if the file can't be created
+    pub fn create(path: &Path) -> Result<Recorder, Error> {
+        Ok(Recorder {
+            writer: Mutex::new(BufWriter::new(File::create(path)?)),
+            start: Instant::now(),
+        })
+    }
+
+    /// Append an event received now
+    ///
+    /// # Errors
+    /// Returns an `Error` if the event can't be written
+    pub fn record(&self, event: &Event) -> Result<(), Error> {
+        let recorded = RecordedEvent {
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+            event: event.clone(),
+        };
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, &recorded)?;
+        writer.write_all(b"\n")?;
+        writer.flush()
+    }
+}
+
+/// Iterator over the events of a session file, returning each event at its recorded time
+///
+/// The first call to `next` starts the clock, then each event is returned once the time elapsed
+/// since the start of the recording, divided by the speed, has passed.
+/// Pass it to `Watch::replay` to run the events through the filters, layers and handler of a watch.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// use notify::Event;
+/// use watchcrab::replay::Replay;
+/// use watchcrab::watch::Watch;
+///
+/// let events = vec!["create".to_string()];
+/// let f = |event: Event| -> Result<(), String> {
+///     println!("{:?}", event.paths);
+///     Ok(())
+/// };
+/// let replay = Replay::open(Path::new("session.jsonl")).unwrap().speed(10.0);
+/// Watch::new_fallible(Path::new("./"), true, &events, f, 1).replay(replay).unwrap();
+/// ```
+pub struct Replay {
+    path: PathBuf,
+    lines: Lines<BufReader<File>>,
+    speed: f64,
+    start: Option<Instant>,
+}
+
+impl Replay {
+    /// Open a session file written by `Recorder`
+    ///
+    /// # Errors
+    /// Returns an `Error` if the file can't be opened
+    pub fn open(path: &Path) -> Result<Replay, Error> {
+        Ok(Replay {
+            path: path.to_path_buf(),
+            lines: BufReader::new(File::open(path)?).lines(),
+            speed: 1.0,
+            start: None,
+        })
+    }
+
+    /// Replay `speed` times faster than recorded, 1 by default, 0 to return the events without waiting
+    pub fn speed(mut self, speed: f64) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Time to wait from the start of the replay before returning an event, `None` to return it right away
+    fn delay(&self, elapsed_ms: u64) -> Option<Duration> {
+        if self.speed > 0.0 && self.speed.is_finite() {
+            Some(Duration::from_secs_f64(
+                elapsed_ms as f64 / 1000.0 / self.speed,
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl Iterator for Replay {
+    type Item = Result<Event, Error>;
+
+    /// Next event of the session, an `Error` of kind `InvalidData` if a line is not a recorded event
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = loop {
+            match self.lines.next()? {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => break line,
+                Err(e) => return Some(Err(e)),
+            }
+        };
+        let recorded: RecordedEvent = match serde_json::from_str(&line) {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid session file {}: {}", self.path.display(), e),
+                )))
+            }
+        };
+
+        let start = *self.start.get_or_insert_with(Instant::now);
+        if let Some(delay) = self.delay(recorded.elapsed_ms) {
+            thread::sleep((start + delay).saturating_duration_since(Instant::now()));
+        }
+        Some(Ok(recorded.event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::Debounce;
+    use crate::test_util::TempDir;
+    use crate::watch::Watch;
+    use notify::event::{CreateKind, DataChange, ModifyKind};
+    use notify::EventKind;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_record_and_replay_through_watch() {
+        let dir = TempDir::new("replay");
+        let path = dir.join("session.jsonl");
+
+        let create = Event::new(EventKind::Create(CreateKind::File)).add_path("/src/a.rs".into());
+        let modify = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)))
+            .add_path("/src/a.rs".into());
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(&create).unwrap();
+        recorder.record(&modify).unwrap();
+        recorder.record(&modify).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"elapsed_ms\":2000,\"event\":{\"type\":{\"remove\":{\"kind\":\"file\"}},\"paths\":[\"/src/b.rs\"],\"attrs\":{}}}\n")
+            .unwrap();
+
+        let replayed: Vec<Event> = Replay::open(&path)
+            .unwrap()
+            .speed(0.0)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(replayed[..3], [create, modify.clone(), modify.clone()]);
+
+        // The events filter drops the create event, the debounce merges the modifications
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let calls = Arc::clone(&handled);
+        let f = move |event: Event| -> Result<(), String> {
+            calls.lock().unwrap().push(event);
+            Ok(())
+        };
+        let events = vec!["modify".to_string(), "remove".to_string()];
+        let start = Instant::now();
+        Watch::new_fallible(&dir, false, &events, f, 1)
+            .layer(Debounce::new(Duration::from_millis(50)))
+            .replay(Replay::open(&path).unwrap().speed(20.0))
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        let handled = handled.lock().unwrap();
+        assert_eq!(handled.len(), 2);
+        assert_eq!(handled[0], modify);
+        assert_eq!(handled[1].paths, vec![PathBuf::from("/src/b.rs")]);
+
+        std::fs::write(&path, "not json\n").unwrap();
+        let error = Replay::open(&path).unwrap().next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,365 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tracing::error;
+
+/// Default number of rotated files kept next to the log file
+pub const DEFAULT_RETAIN: usize = 5;
+
+/// Time-based rotation period, periods start at midnight UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    fn seconds(&self) -> u64 {
+        match self {
+            Rotation::Hourly => 60 * 60,
+            Rotation::Daily => 24 * 60 * 60,
+        }
+    }
+
+    /// Number of the period containing `time`
+    fn period(&self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        secs / self.seconds()
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ => Err(format!(
+                "Invalid rotation period '{}', expected one of: hourly, daily",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rotation::Hourly => write!(f, "hourly"),
+            Rotation::Daily => write!(f, "daily"),
+        }
+    }
+}
+
+/// When and how a log file is rotated
+///
+/// * `max_size` - Rotate before a record would make the file larger than this number of bytes, `None` to never rotate on size
+/// * `every` - Rotate when a new period starts, `None` to never rotate on time
+/// * `retain` - Number of rotated files kept, the oldest ones are deleted
+/// * `compress` - Compress the rotated files with gzip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RotationOptions {
+    pub max_size: Option<u64>,
+    pub every: Option<Rotation>,
+    pub retain: usize,
+    pub compress: bool,
+}
+
+impl Default for RotationOptions {
+    fn default() -> Self {
+        RotationOptions {
+            max_size: None,
+            every: None,
+            retain: DEFAULT_RETAIN,
+            compress: false,
+        }
+    }
+}
+
+/// Log file kept open across writes and rotated by size and time
+///
+/// Rotated files are named after the log file with a number, `1` being the most recent: `out.log.1`, `out.log.2`,
+/// or `out.log.1.gz` with compression. The rotated file is compressed by a background thread, it is named `out.log.1`
+/// until the compression is done.
+pub struct RotatingWriter {
+    path: PathBuf,
+    options: RotationOptions,
+    writer: BufWriter<File>,
+    size: u64,
+    period: Option<u64>,
+    // Thread compressing the last rotated file, waited for before the next rotation
+    compression: Option<JoinHandle<()>>,
+}
+
+impl RotatingWriter {
+    /// Open the log file for appending, creating it if needed
+    ///
+    /// An existing log file last written in a previous period is rotated on the first write.
+    ///
+    /// # Errors
+    /// Returns an `Error` if the log file can't be opened
+    pub fn open(path: &Path, options: RotationOptions) -> Result<RotatingWriter, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let period = options
+            .every
+            .map(|every| every.period(metadata.modified().unwrap_or_else(|_| SystemTime::now())));
+        Ok(RotatingWriter {
+            path: path.to_path_buf(),
+            options,
+            writer: BufWriter::new(file),
+            size: metadata.len(),
+            period,
+            compression: None,
+        })
+    }
+
+    /// Write a record followed by a newline, rotating the log file first if needed
+    ///
+    /// The record is buffered, see `RotatingWriter::flush`.
+    ///
+    /// # Errors
+    /// Returns an `Error` if the log file can't be rotated or written, the record is still written when only the rotation failed
+    pub fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        let len = line.len() as u64 + 1;
+        let rotated = match self.needs_rotation(len) {
+            true => self.rotate(),
+            false => Ok(()),
+        };
+        writeln!(self.writer, "{}", line)?;
+        self.size += len;
+        rotated
+    }
+
+    /// Write the buffered records to the log file
+    ///
+    /// # Errors
+    /// Returns an `Error` if the log file can't be written
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+
+    /// Flush the buffered records and wait for them to reach the disk
+    ///
+    /// # Errors
+    /// Returns an `Error` if the log file can't be written or synced
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        match self.writer.get_ref().sync_data() {
+            // Special files like /dev/null can't be synced
+            Err(e) if e.kind() == ErrorKind::InvalidInput => Ok(()),
+            result => result,
+        }
+    }
+
+    fn needs_rotation(&self, len: u64) -> bool {
+        let too_large = match self.options.max_size {
+            // A record larger than the limit still goes to an empty file
+            Some(max_size) => self.size > 0 && self.size + len > max_size,
+            None => false,
+        };
+        let new_period = match (self.options.every, self.period) {
+            (Some(every), Some(period)) => every.period(SystemTime::now()) != period,
+            _ => false,
+        };
+        too_large || new_period
+    }
+
+    /// Move the log file to `<path>.1`, shifting the older rotated files and deleting the ones beyond `retain`
+    ///
+    /// The log file is reopened even if it couldn't be moved, so the next records still go to `path`.
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        let moved = self.move_log_file();
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.writer = BufWriter::new(file);
+        self.period = self
+            .options
+            .every
+            .map(|every| every.period(SystemTime::now()));
+        moved
+    }
+
+    fn move_log_file(&mut self) -> Result<(), Error> {
+        // The rotated files are shifted once the previous one is compressed
+        self.wait_compression();
+
+        let extension = if self.options.compress { ".gz" } else { "" };
+        let retain = self.options.retain;
+        remove_if_exists(&self.rotated_path(retain.max(1), extension))?;
+        for index in (1..retain).rev() {
+            let from = self.rotated_path(index, extension);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1, extension))?;
+            }
+        }
+
+        if retain == 0 {
+            return fs::remove_file(&self.path);
+        }
+        let rotated = self.rotated_path(1, "");
+        fs::rename(&self.path, &rotated)?;
+        if self.options.compress {
+            let compressed = self.rotated_path(1, extension);
+            self.compression = Some(thread::spawn(move || {
+                if let Err(e) =
+                    compress(&rotated, &compressed).and_then(|_| fs::remove_file(&rotated))
+                {
+                    error!(path = %rotated.display(), error = %e, "Unable to compress rotated log file");
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    fn wait_compression(&mut self) {
+        if let Some(compression) = self.compression.take() {
+            let _ = compression.join();
+        }
+    }
+
+    fn rotated_path(&self, index: usize, extension: &str) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}{}", index, extension));
+        PathBuf::from(name)
+    }
+}
+
+impl Drop for RotatingWriter {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+        self.wait_compression();
+    }
+}
+
+/// Parse a size in bytes with an optional `K`, `M` or `G` suffix (powers of 1024), like `100M` or `512KB`
+///
+/// # Errors
+/// Returns an error message if the size is not a number followed by a known suffix
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let upper = size.trim().to_ascii_uppercase();
+    let digits = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, multiplier) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 1 << 10),
+        Some('M') => (&digits[..digits.len() - 1], 1 << 20),
+        Some('G') => (&digits[..digits.len() - 1], 1 << 30),
+        _ => (digits, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| {
+            format!(
+                "Invalid size '{}', expected a number of bytes with an optional K, M or G suffix",
+                size
+            )
+        })
+}
+
+fn compress(from: &Path, to: &Path) -> Result<(), Error> {
+    let mut input = File::open(from)?;
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(to)?), Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.flush()
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_rotate_on_size_with_retention_and_compression() {
+        let dir = TempDir::new("rotate");
+        let path = dir.join("out.log");
+
+        let options = RotationOptions {
+            max_size: Some(10),
+            retain: 2,
+            compress: true,
+            ..RotationOptions::default()
+        };
+        let mut writer = RotatingWriter::open(&path, options).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            writer.write_line(line).unwrap();
+        }
+        drop(writer); // Wait for the compression of the last rotated file
+
+        let decompress = |index: usize| {
+            let mut content = String::new();
+            GzDecoder::new(File::open(dir.join(format!("out.log.{}.gz", index))).unwrap())
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        };
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(decompress(1), "third\n");
+        assert_eq!(decompress(2), "second\n");
+        assert!(!dir.join("out.log.3.gz").exists());
+        assert!(!dir.join("out.log.1").exists());
+    }
+
+    #[test]
+    fn test_failed_rotation_keeps_writing_to_the_log_file() {
+        let dir = TempDir::new("rotate-failure");
+        let path = dir.join("out.log");
+        // A directory in place of the rotated file makes the rotation fail
+        fs::create_dir_all(dir.join("out.log.1").join("blocked")).unwrap();
+
+        let options = RotationOptions {
+            max_size: Some(10),
+            retain: 1,
+            ..RotationOptions::default()
+        };
+        let mut writer = RotatingWriter::open(&path, options).unwrap();
+        writer.write_line("first").unwrap();
+        assert!(writer.write_line("second").is_err());
+        writer.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+
+        fs::remove_dir_all(dir.join("out.log.1")).unwrap();
+        writer.write_line("third").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("out.log.1")).unwrap(),
+            "first\nsecond\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("100M"), Ok(100 << 20));
+        assert_eq!(parse_size("2kb"), Ok(2048));
+        assert!(parse_size("big").is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use globset::{GlobBuilder, GlobMatcher};
+use notify::Event;
+
+use crate::event::matches_filter;
+use crate::handler::{Handler, HandlerError};
+
+/// Glob pattern matched against the paths of the events
+///
+/// A pattern without `/`, like `*.rs`, is matched against the file name, otherwise against the path relative to a root.
+/// `*` does not cross directories, use `**` to match any number of directories.
+#[derive(Debug, Clone)]
+pub struct PathGlob {
+    matcher: GlobMatcher,
+    file_name_only: bool,
+}
+
+impl PathGlob {
+    /// # Errors
+    /// Returns an `Error` of kind `InvalidInput` if the pattern is not a valid glob
+    pub fn new(pattern: &str) -> Result<PathGlob, Error> {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        Ok(PathGlob {
+            matcher: glob.compile_matcher(),
+            file_name_only: !pattern.contains('/'),
+        })
+    }
+
+    /// Whether `path` matches, `relative` being the path relative to the root the pattern applies to
+    pub fn is_match(&self, path: &Path, relative: &Path) -> bool {
+        if self.file_name_only {
+            path.file_name()
+                .is_some_and(|name| self.matcher.is_match(name))
+        } else {
+            self.matcher.is_match(relative)
+        }
+    }
+}
+
+/// Handler registered in a `Router`, called for the events matching all its predicates
+///
+/// A route without predicates matches every event.
+pub struct Route {
+    handler: Handler,
+    glob: Option<PathGlob>,
+    kinds: Option<Vec<String>>,
+    root: Option<PathBuf>,
+}
+
+impl Route {
+    /// Route calling `f` for the matching events
+    pub fn new<F>(f: F) -> Route
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        Route::new_fallible(move |event: Event| -> Result<(), HandlerError> {
+            f(event);
+            Ok(())
+        })
+    }
+
+    /// Same as `Route::new` with a handler returning a `Result`
+    pub fn new_fallible<F, E>(f: F) -> Route
+    where
+        F: Fn(Event) -> Result<(), E> + Send + Sync + 'static,
+        E: Into<HandlerError>,
+    {
+        Route {
+            handler: Arc::new(move |event| f(event).map_err(Into::into)),
+            glob: None,
+            kinds: None,
+            root: None,
+        }
+    }
+
+    /// Only match the paths matching a glob pattern, see `PathGlob`
+    ///
+    /// A pattern containing `/` is matched against the path relative to the root of the route (see `Route::root`),
+    /// or against the absolute path if the route has no root.
+    ///
+    /// # Errors
+    /// Returns an `Error` of kind `InvalidInput` if the pattern is not a valid glob
+    pub fn glob(mut self, pattern: &str) -> Result<Self, Error> {
+        self.glob = Some(PathGlob::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Only match the events of the given kinds, with the same names as the events filter of `Watch::new`
+    pub fn kinds(mut self, kinds: &[&str]) -> Self {
+        self.kinds = Some(kinds.iter().map(|kind| kind.to_string()).collect());
+        self
+    }
+
+    /// Only match the paths inside `root`, the glob pattern is then relative to it
+    pub fn root(mut self, root: &Path) -> Self {
+        // Event paths are canonical, the root must be too for the prefix to match
+        self.root = Some(root.canonicalize().unwrap_or_else(|_| root.to_path_buf()));
+        self
+    }
+
+    /// Whether the route handles the event
+    ///
+    /// An event with several paths (for example a rename) matches if any of its paths matches.
+    pub fn matches(&self, event: &Event) -> bool {
+        if let Some(kinds) = &self.kinds {
+            if !matches_filter(event, kinds) {
+                return false;
+            }
+        }
+        if self.glob.is_none() && self.root.is_none() {
+            return true;
+        }
+        event.paths.iter().any(|path| self.matches_path(path))
+    }
+
+    fn matches_path(&self, path: &Path) -> bool {
+        let relative = match &self.root {
+            Some(root) => match path.strip_prefix(root) {
+                Ok(relative) => relative,
+                Err(_) => return false,
+            },
+            None => path,
+        };
+        match &self.glob {
+            Some(glob) => glob.is_match(path, relative),
+            None => true,
+        }
+    }
+}
+
+/// Dispatches each event to every matching route, so one watch can run different handlers for different files
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// use notify::Event;
+/// use watchcrab::router::{Route, Router};
+/// use watchcrab::watch::Watch;
+///
+/// let project = Path::new("./");
+/// let router = Router::new()
+///     .route(Route::new(|_: Event| println!("recompile")).glob("*.rs").unwrap())
+///     .route(Route::new(|_: Event| println!("reload")).glob("*.toml").unwrap().kinds(&["modify"]))
+///     .route(Route::new(|event: Event| println!("sync {:?}", event.paths)).root(project).glob("assets/**").unwrap());
+///
+/// let events = vec!["all".to_string()];
+/// Watch::with_router(project, true, &events, router, 4).start();
+/// ```
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Register a route, the routes matching an event are called in the order they were registered
+    pub fn route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Call the handlers of the routes matching the event
+    ///
+    /// Every matching route is called even if a previous one failed.
+    ///
+    /// # Errors
+    /// Returns the error of the failed route, or the errors of all the failed routes joined with `; `
+    pub fn dispatch(&self, event: Event) -> Result<(), HandlerError> {
+        let mut errors: Vec<HandlerError> = Vec::new();
+        for route in self.routes.iter().filter(|route| route.matches(&event)) {
+            if let Err(e) = (route.handler)(event.clone()) {
+                errors.push(e);
+            }
+        }
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<String>>()
+                .join("; ")
+                .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, ModifyKind};
+    use notify::EventKind;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_dispatch_to_matching_routes() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let calls = Arc::clone(&calls);
+            move |_: Event| calls.lock().unwrap().push(name)
+        };
+        let router = Router::new()
+            .route(Route::new(record("rust")).glob("*.rs").unwrap())
+            .route(
+                Route::new(record("toml"))
+                    .glob("*.toml")
+                    .unwrap()
+                    .kinds(&["modify"]),
+            )
+            .route(
+                Route::new(record("assets"))
+                    .root(Path::new("/project"))
+                    .glob("assets/**")
+                    .unwrap(),
+            );
+
+        let create =
+            |path: &str| Event::new(EventKind::Create(CreateKind::File)).add_path(path.into());
+        router.dispatch(create("/project/src/main.rs")).unwrap();
+        router.dispatch(create("/project/Cargo.toml")).unwrap();
+        router
+            .dispatch(
+                Event::new(EventKind::Modify(ModifyKind::Any))
+                    .add_path("/project/Cargo.toml".into()),
+            )
+            .unwrap();
+        router
+            .dispatch(create("/project/assets/img/logo.rs"))
+            .unwrap();
+        router.dispatch(create("/other/assets/logo.png")).unwrap();
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["rust", "toml", "rust", "assets"]
+        );
+    }
+}
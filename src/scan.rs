@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::{Error, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::MetadataExt;
+
+use notify::event::{CreateKind, Flag, ModifyKind, RemoveKind};
+use notify::{Event, EventKind};
+use tracing::warn;
+
+/// List the files under a root, sorted by path
+///
+/// Symbolic links to directories are not followed. Subdirectories that can't be read are skipped.
+///
+/// # Arguments
+/// * `root` - Directory to walk
+/// * `recursive` - Walk subdirectories, otherwise only the top level files are listed
+///
+/// # Errors
+/// Returns an `Error` if the root directory can't be read
+pub fn walk(root: &Path, recursive: bool) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    let mut is_root = true;
+
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if is_root => return Err(e),
+            Err(e) => {
+                warn!(dir = %dir.display(), error = %e, "Unable to read directory");
+                continue;
+            }
+        };
+        is_root = false;
+
+        for entry in entries.flatten() {
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                if recursive {
+                    pending.push(entry.path());
+                }
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Build the `create` event synthesized for a file that already existed when the watcher started
+pub fn synthetic_create(path: PathBuf) -> Event {
+    Event::new(EventKind::Create(CreateKind::File)).add_path(path)
+}
+
+/// Build the event handed to the handlers when a root had to be rescanned, e.g. after an event queue overflow
+///
+/// It has the `Other` kind, the `Rescan` flag and the root as path.
+pub fn rescan_event(root: PathBuf) -> Event {
+    Event::new(EventKind::Other)
+        .set_flag(Flag::Rescan)
+        .add_path(root)
+}
+
+/// Metadata of a file used to detect changes between two scans
+///
+/// * `size` - Size in bytes
+/// * `mtime` - Modification time in nanoseconds since the Unix epoch
+/// * `inode` - Inode number, always 0 on platforms without inodes
+/// * `hash` - Hash of the contents, only computed when requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMeta {
+    pub size: u64,
+    pub mtime: u128,
+    pub inode: u64,
+    pub hash: Option<u64>,
+}
+
+impl FileMeta {
+    /// Read the metadata of a file, hashing its contents if `hash` is true
+    ///
+    /// # Errors
+    /// Returns an `Error` if the file can't be read
+    pub fn read(path: &Path, hash: bool) -> Result<FileMeta, Error> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileMeta {
+            size: metadata.len(),
+            mtime: metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0),
+            inode: inode(&metadata),
+            hash: if hash { Some(hash_file(path)?) } else { None },
+        })
+    }
+
+    /// Whether the file changed, contents hashes are only compared when both sides have one
+    pub fn changed(&self, other: &FileMeta) -> bool {
+        let hash_changed = match (self.hash, other.hash) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        };
+        self.size != other.size
+            || self.mtime != other.mtime
+            || self.inode != other.inode
+            || hash_changed
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn inode(metadata: &Metadata) -> u64 {
+    metadata.ino()
+}
+
+#[cfg(not(target_family = "unix"))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// Hash the contents of a file with 64-bit FNV-1a, which is stable across platforms and Rust versions
+///
+/// # Errors
+/// Returns an `Error` if the file can't be read
+pub fn hash_file(path: &Path) -> Result<u64, Error> {
+    let mut file = File::open(path)?;
+    let mut buffer = [0u8; 8192];
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for byte in &buffer[..read] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(hash)
+}
+
+/// State of the files under one or more roots at a point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub entries: BTreeMap<PathBuf, FileMeta>,
+}
+
+impl Snapshot {
+    /// Scan the given roots
+    ///
+    /// Files removed while scanning are ignored.
+    ///
+    /// # Errors
+    /// Returns an `Error` if a root can't be read
+    pub fn take(roots: &[PathBuf], recursive: bool, hash: bool) -> Result<Snapshot, Error> {
+        let mut entries = BTreeMap::new();
+        for root in roots {
+            for file in walk(root, recursive)? {
+                if let Ok(meta) = FileMeta::read(&file, hash) {
+                    entries.insert(file, meta);
+                }
+            }
+        }
+        Ok(Snapshot { entries })
+    }
+
+    /// Update the entry of a path after a live event
+    ///
+    /// Existing files are read again, missing paths are removed with every entry below them. Directories are ignored.
+    pub fn update_path(&mut self, path: &Path, hash: bool) {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => (),
+            Ok(_) => {
+                if let Ok(meta) = FileMeta::read(path, hash) {
+                    self.entries.insert(path.to_path_buf(), meta);
+                }
+            }
+            Err(_) => {
+                self.remove_tree(path);
+            }
+        }
+    }
+
+    /// Scan a root again and synthesize the events for every difference with the cached entries below it
+    ///
+    /// # Errors
+    /// Returns an `Error` if the root can't be read, the cached entries are left untouched
+    pub fn rescan(
+        &mut self,
+        root: &Path,
+        recursive: bool,
+        hash: bool,
+    ) -> Result<Vec<Event>, Error> {
+        let current = Snapshot::take(&[root.to_path_buf()], recursive, hash)?;
+        let cached = Snapshot {
+            entries: self.remove_tree(root),
+        };
+        let events = cached.diff(&current);
+        self.entries.extend(current.entries);
+        Ok(events)
+    }
+
+    /// Remove the entries of a path and every path below it
+    pub(crate) fn remove_tree(&mut self, path: &Path) -> BTreeMap<PathBuf, FileMeta> {
+        // Paths are ordered by component, so the entries below a path directly follow it
+        let removed: Vec<PathBuf> = self
+            .entries
+            .range(path.to_path_buf()..)
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .cloned()
+            .collect();
+        removed
+            .into_iter()
+            .filter_map(|p| self.entries.remove_entry(&p))
+            .collect()
+    }
+
+    /// Synthesize the events that turn `self` into `current`
+    ///
+    /// Created files get a `Create(File)` event, changed files a `Modify(Any)` event and missing files a `Remove(File)` event.
+    pub fn diff(&self, current: &Snapshot) -> Vec<Event> {
+        let mut events = Vec::new();
+        for (path, meta) in &current.entries {
+            match self.entries.get(path) {
+                None => events.push(synthetic_create(path.clone())),
+                Some(old) if old.changed(meta) => events
+                    .push(Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path.clone())),
+                Some(_) => (),
+            }
+        }
+        for path in self.entries.keys() {
+            if !current.entries.contains_key(path) {
+                events.push(Event::new(EventKind::Remove(RemoveKind::File)).add_path(path.clone()));
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_walk_respects_recursion() {
+        let dir = TempDir::new("walk");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), "").unwrap();
+        fs::write(dir.join("sub").join("b.txt"), "").unwrap();
+
+        assert_eq!(walk(&dir, false).unwrap(), vec![dir.join("a.txt")]);
+        assert_eq!(
+            walk(&dir, true).unwrap(),
+            vec![dir.join("a.txt"), dir.join("sub").join("b.txt")]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let meta = FileMeta {
+            size: 1,
+            mtime: 1,
+            inode: 1,
+            hash: None,
+        };
+        let mut old = Snapshot::default();
+        old.entries.insert(PathBuf::from("/kept"), meta);
+        old.entries.insert(PathBuf::from("/changed"), meta);
+        old.entries.insert(PathBuf::from("/removed"), meta);
+
+        let mut current = old.clone();
+        current.entries.remove(Path::new("/removed"));
+        current
+            .entries
+            .insert(PathBuf::from("/changed"), FileMeta { size: 2, ..meta });
+        current.entries.insert(PathBuf::from("/created"), meta);
+
+        let events = old.diff(&current);
+        assert_eq!(events.len(), 3);
+        assert!(events[0].kind.is_modify() && events[0].paths[0] == Path::new("/changed"));
+        assert!(events[1].kind.is_create() && events[1].paths[0] == Path::new("/created"));
+        assert!(events[2].kind.is_remove() && events[2].paths[0] == Path::new("/removed"));
+    }
+
+    #[test]
+    fn test_rescan_only_affects_root() {
+        let dir = TempDir::new("rescan");
+        let other = dir.join("other");
+        let root = dir.join("root");
+        fs::create_dir_all(&other).unwrap();
+        fs::create_dir_all(&root).unwrap();
+        fs::write(other.join("a.txt"), "").unwrap();
+        fs::write(root.join("b.txt"), "").unwrap();
+
+        let mut tree = Snapshot::take(&[other.clone(), root.clone()], true, false).unwrap();
+        fs::remove_file(other.join("a.txt")).unwrap();
+        fs::remove_file(root.join("b.txt")).unwrap();
+        fs::write(root.join("c.txt"), "").unwrap();
+
+        let events = tree.rescan(&root, true, false).unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].kind.is_create() && events[0].paths[0] == root.join("c.txt"));
+        assert!(events[1].kind.is_remove() && events[1].paths[0] == root.join("b.txt"));
+        // The other root keeps its cached entries until it's rescanned
+        assert!(tree.entries.contains_key(&other.join("a.txt")));
+    }
+}
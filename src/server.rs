@@ -0,0 +1,805 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use notify::Event;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::event::{matches_filter, EventRecord};
+use crate::router::PathGlob;
+
+/// Default number of events waiting to be sent to a client before it is considered too slow
+pub const DEFAULT_CLIENT_BUFFER: usize = 1024;
+
+/// Time given to a client to send its subscription after connecting
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time given to the clients to receive their pending events when the server is dropped
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Address of a server: `unix:<path>` for a Unix domain socket, `tcp:<host>:<port>` or `<host>:<port>` for TCP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerAddr {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl FromStr for ServerAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(String::from("Missing socket path in 'unix:'"));
+            }
+            return Ok(ServerAddr::Unix(PathBuf::from(path)));
+        }
+        let address = s.strip_prefix("tcp:").unwrap_or(s);
+        match address.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => {
+                Ok(ServerAddr::Tcp(address.to_string()))
+            }
+            _ => Err(format!(
+                "Invalid address '{}', expected unix:<path>, tcp:<host>:<port> or <host>:<port>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ServerAddr::Tcp(address) => write!(f, "tcp:{}", address),
+        }
+    }
+}
+
+/// Events a client wants to receive, sent as a single JSON line right after connecting
+///
+/// * `include` - Glob patterns of the paths to receive, see `router::PathGlob`, every path if empty
+/// * `exclude` - Glob patterns of the paths to skip, applied after `include`
+/// * `kinds` - Normalized kinds of the events to receive, see `event::kind_name`, every kind if empty
+///
+/// Patterns containing `/` are matched against the path relative to the watched directory.
+/// An empty line or `{}` subscribes to every event.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Subscription {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub kinds: Vec<String>,
+}
+
+impl Subscription {
+    /// Parse the subscription line sent by a client
+    ///
+    /// # Errors
+    /// Returns an error message if the line is not a valid subscription or contains an invalid glob pattern
+    pub fn parse(line: &str) -> Result<Subscription, String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(Subscription::default());
+        }
+        let subscription: Subscription =
+            serde_json::from_str(line).map_err(|e| format!("Invalid subscription: {}", e))?;
+        subscription.filter(Path::new(""))?;
+        Ok(subscription)
+    }
+
+    /// Compile the subscription, relative patterns being matched inside `root`
+    fn filter(&self, root: &Path) -> Result<SubscriptionFilter, String> {
+        let globs = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| PathGlob::new(pattern).map_err(|e| e.to_string()))
+                .collect::<Result<Vec<PathGlob>, String>>()
+        };
+        Ok(SubscriptionFilter {
+            root: root.to_path_buf(),
+            include: globs(&self.include)?,
+            exclude: globs(&self.exclude)?,
+            kinds: self.kinds.clone(),
+        })
+    }
+}
+
+struct SubscriptionFilter {
+    root: PathBuf,
+    include: Vec<PathGlob>,
+    exclude: Vec<PathGlob>,
+    kinds: Vec<String>,
+}
+
+impl SubscriptionFilter {
+    /// An event with several paths (for example a rename) matches if any of its paths matches
+    fn matches(&self, event: &Event) -> bool {
+        if !self.kinds.is_empty() && !matches_filter(event, &self.kinds) {
+            return false;
+        }
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return true;
+        }
+        event.paths.iter().any(|path| {
+            let relative = path.strip_prefix(&self.root).unwrap_or(path);
+            (self.include.is_empty()
+                || self
+                    .include
+                    .iter()
+                    .any(|glob| glob.is_match(path, relative)))
+                && !self
+                    .exclude
+                    .iter()
+                    .any(|glob| glob.is_match(path, relative))
+        })
+    }
+}
+
+/// What to do with a client that doesn't read its events fast enough
+///
+/// * `Disconnect` - Close the connection, the client can reconnect knowing it missed events
+/// * `Drop` - Keep the connection and skip the events until the client catches up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    Disconnect,
+    Drop,
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disconnect" => Ok(SlowClientPolicy::Disconnect),
+            "drop" => Ok(SlowClientPolicy::Drop),
+            _ => Err(format!(
+                "Invalid slow client policy '{}', expected one of: disconnect, drop",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SlowClientPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlowClientPolicy::Disconnect => write!(f, "disconnect"),
+            SlowClientPolicy::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Options of a `Server`
+///
+/// * `client_buffer` - Number of events waiting to be sent to a client before `slow_client` applies
+/// * `slow_client` - What to do with a client whose buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerOptions {
+    pub client_buffer: usize,
+    pub slow_client: SlowClientPolicy,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            client_buffer: DEFAULT_CLIENT_BUFFER,
+            slow_client: SlowClientPolicy::Disconnect,
+        }
+    }
+}
+
+/// Connection accepted on a Unix domain socket or TCP listener
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> Result<Stream, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+struct Client {
+    id: u64,
+    filter: SubscriptionFilter,
+    tx: Sender<Arc<str>>,
+    stream: Stream,
+    behind: bool,
+}
+
+#[derive(Default)]
+struct Clients {
+    next_id: u64,
+    clients: Vec<Client>,
+}
+
+/// Thread accepting the clients of an address, and the address to connect to to wake it up
+struct Listener {
+    addr: ServerAddr,
+    thread: JoinHandle<()>,
+}
+
+/// Thread serving an accepted client
+struct Connection {
+    stream: Stream,
+    thread: JoinHandle<()>,
+}
+
+/// Server streaming the events as NDJSON (one `event::EventRecord` per line) to any number of clients
+///
+/// Each client first sends its `Subscription` as a JSON line, then receives the matching events. Every client has its own
+/// buffer and writer thread, so `Server::broadcast` never waits for a client: a client that doesn't keep up is handled
+/// according to `ServerOptions::slow_client`. Invalid subscriptions are answered with `{"error": "<message>"}` before
+/// closing the connection.
+///
+/// Dropping the server stops accepting clients, gives the clients a second to receive the events already broadcast,
+/// then closes the connections and waits for every thread of the server.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// use std::sync::Arc;
+/// use notify::Event;
+/// use watchcrab::server::{Server, ServerOptions};
+/// use watchcrab::watch::Watch;
+///
+/// let listen = vec!["unix:/tmp/watchcrab.sock".parse().unwrap(), "127.0.0.1:7070".parse().unwrap()];
+/// let root = Path::new("./");
+/// let server = Server::bind(&listen, root, ServerOptions::default()).unwrap();
+///
+/// let events = vec!["all".to_string()];
+/// let f: Arc<Box<dyn Fn(Event) + Send + Sync>> = Arc::new(Box::new(move |event| server.broadcast(&event)));
+/// Watch::new(root, true, &events, f, 1).start();
+/// ```
+pub struct Server {
+    clients: Arc<Mutex<Clients>>,
+    options: ServerOptions,
+    socket_paths: Vec<PathBuf>,
+    stopping: Arc<AtomicBool>,
+    listeners: Vec<Listener>,
+    connections: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl Server {
+    /// Listen on every address and accept the clients in background threads
+    ///
+    /// # Arguments
+    /// * `addrs` - Addresses to listen on, a stale Unix domain socket file is replaced
+    /// * `root` - Watched directory, the relative glob patterns of the subscriptions are matched inside it
+    /// * `options` - Buffering of the events sent to the clients
+    ///
+    /// # Errors
+    /// Returns an `Error` if an address can't be bound, or is a Unix domain socket on a platform without them
+    pub fn bind(
+        addrs: &[ServerAddr],
+        root: &Path,
+        options: ServerOptions,
+    ) -> Result<Server, Error> {
+        // Event paths are canonical, the root must be too for the prefix to match
+        let root = Arc::new(root.canonicalize().unwrap_or_else(|_| root.to_path_buf()));
+        let clients = Arc::new(Mutex::new(Clients::default()));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let connections: Arc<Mutex<Vec<Connection>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut socket_paths = Vec::new();
+        let mut listeners = Vec::new();
+
+        for addr in addrs {
+            // Returns false once the server is dropped, to stop accepting clients
+            let accept = {
+                let clients = Arc::clone(&clients);
+                let root = Arc::clone(&root);
+                let stopping = Arc::clone(&stopping);
+                let connections = Arc::clone(&connections);
+                move |stream: Stream| {
+                    if stopping.load(Ordering::SeqCst) {
+                        return false;
+                    }
+                    let Ok(handle) = stream.try_clone() else {
+                        return true;
+                    };
+                    let clients = Arc::clone(&clients);
+                    let root = Arc::clone(&root);
+                    let client_stopping = Arc::clone(&stopping);
+                    let thread = thread::spawn(move || {
+                        serve_client(stream, &root, &clients, &client_stopping, options)
+                    });
+                    let mut connections = connections.lock().unwrap();
+                    connections.retain(|connection| !connection.thread.is_finished());
+                    connections.push(Connection {
+                        stream: handle,
+                        thread,
+                    });
+                    true
+                }
+            };
+            match addr {
+                ServerAddr::Tcp(address) => {
+                    let listener = TcpListener::bind(address)?;
+                    let mut local_addr = listener.local_addr()?;
+                    if local_addr.ip().is_unspecified() {
+                        local_addr.set_ip(match local_addr {
+                            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                        });
+                    }
+                    let thread = thread::spawn(move || {
+                        for stream in listener.incoming().flatten() {
+                            if !accept(Stream::Tcp(stream)) {
+                                break;
+                            }
+                        }
+                    });
+                    listeners.push(Listener {
+                        addr: ServerAddr::Tcp(local_addr.to_string()),
+                        thread,
+                    });
+                }
+                #[cfg(unix)]
+                ServerAddr::Unix(path) => {
+                    remove_stale_socket(path)?;
+                    let listener = UnixListener::bind(path)?;
+                    socket_paths.push(path.clone());
+                    let thread = thread::spawn(move || {
+                        for stream in listener.incoming().flatten() {
+                            if !accept(Stream::Unix(stream)) {
+                                break;
+                            }
+                        }
+                    });
+                    listeners.push(Listener {
+                        addr: addr.clone(),
+                        thread,
+                    });
+                }
+                #[cfg(not(unix))]
+                ServerAddr::Unix(_) => {
+                    return Err(Error::new(
+                        ErrorKind::Unsupported,
+                        "Unix domain sockets are not supported on this platform",
+                    ));
+                }
+            }
+            info!(address = %addr, "Listening");
+        }
+
+        Ok(Server {
+            clients,
+            options,
+            socket_paths,
+            stopping,
+            listeners,
+            connections,
+        })
+    }
+
+    /// Send an event to every client subscribed to it, without waiting for the clients
+    pub fn broadcast(&self, event: &Event) {
+        let mut line = match serde_json::to_string(&EventRecord::new(event)) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(error = %e, "Couldn't serialize event");
+                return;
+            }
+        };
+        line.push('\n');
+        let line: Arc<str> = Arc::from(line);
+
+        let slow_client = self.options.slow_client;
+        let mut clients = self.clients.lock().unwrap();
+        clients.clients.retain_mut(|client| {
+            if !client.filter.matches(event) {
+                return true;
+            }
+            match client.tx.try_send(Arc::clone(&line)) {
+                Ok(()) => {
+                    client.behind = false;
+                    true
+                }
+                Err(TrySendError::Full(_)) => match slow_client {
+                    SlowClientPolicy::Disconnect => {
+                        warn!(client = client.id, "Client too slow, disconnecting");
+                        client.stream.shutdown();
+                        false
+                    }
+                    SlowClientPolicy::Drop => {
+                        if !client.behind {
+                            warn!(client = client.id, "Client too slow, dropping events");
+                            client.behind = true;
+                        }
+                        true
+                    }
+                },
+                Err(TrySendError::Disconnected(_)) => {
+                    debug!(client = client.id, "Client disconnected");
+                    false
+                }
+            }
+        });
+    }
+
+    /// Number of clients currently subscribed
+    pub fn clients(&self) -> usize {
+        self.clients.lock().unwrap().clients.len()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // A connection wakes the accept thread up, it then sees the server stopping
+        for listener in self.listeners.drain(..) {
+            let _ = connect(&listener.addr);
+            let _ = listener.thread.join();
+        }
+        for path in &self.socket_paths {
+            let _ = fs::remove_file(path);
+        }
+
+        // Dropping the senders ends the writer threads once the pending events are sent
+        self.clients.lock().unwrap().clients.clear();
+        let connections = std::mem::take(&mut *self.connections.lock().unwrap());
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while Instant::now() < deadline
+            && connections
+                .iter()
+                .any(|connection| !connection.thread.is_finished())
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Closing the connections unblocks the slow clients and the ones still sending their subscription
+        for connection in connections {
+            connection.stream.shutdown();
+            let _ = connection.thread.join();
+        }
+    }
+}
+
+/// Client of a `Server`, iterating over the events it is subscribed to
+///
+/// The iterator ends when the server closes the connection. An invalid subscription is returned as an `Error` of kind
+/// `InvalidInput` with the message of the server.
+///
+/// # Examples
+///
+/// ```no_run
+/// use watchcrab::server::{Subscriber, Subscription};
+///
+/// let subscription = Subscription {
+///     include: vec![String::from("*.md")],
+///     ..Subscription::default()
+/// };
+/// let addr = "unix:/tmp/watchcrab.sock".parse().unwrap();
+/// for record in Subscriber::connect(&addr, &subscription).unwrap() {
+///     let record = record.unwrap();
+///     println!("{} {:?}", record.kind, record.paths);
+/// }
+/// ```
+pub struct Subscriber {
+    reader: BufReader<Stream>,
+    line: String,
+}
+
+impl Subscriber {
+    /// Connect to a server and send the subscription
+    ///
+    /// # Errors
+    /// Returns an `Error` if the server can't be reached, or the address is a Unix domain socket on a platform without them
+    pub fn connect(addr: &ServerAddr, subscription: &Subscription) -> Result<Subscriber, Error> {
+        let mut stream = connect(addr)?;
+        let subscription = serde_json::to_string(subscription)?;
+        writeln!(stream, "{}", subscription)?;
+        Ok(Subscriber {
+            reader: BufReader::new(stream),
+            line: String::new(),
+        })
+    }
+}
+
+impl Iterator for Subscriber {
+    type Item = Result<EventRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.line.clear();
+        match self.reader.read_line(&mut self.line) {
+            Ok(0) => None,
+            Ok(_) => Some(parse_record(&self.line)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Rejection {
+    error: String,
+}
+
+fn connect(addr: &ServerAddr) -> Result<Stream, Error> {
+    match addr {
+        ServerAddr::Tcp(address) => Ok(Stream::Tcp(TcpStream::connect(address)?)),
+        #[cfg(unix)]
+        ServerAddr::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+        #[cfg(not(unix))]
+        ServerAddr::Unix(_) => Err(Error::new(
+            ErrorKind::Unsupported,
+            "Unix domain sockets are not supported on this platform",
+        )),
+    }
+}
+
+fn parse_record(line: &str) -> Result<EventRecord, Error> {
+    if let Ok(rejection) = serde_json::from_str::<Rejection>(line) {
+        return Err(Error::new(ErrorKind::InvalidInput, rejection.error));
+    }
+    serde_json::from_str(line).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Read the subscription of a new client, register it and write its events until it disconnects
+fn serve_client(
+    stream: Stream,
+    root: &Path,
+    clients: &Mutex<Clients>,
+    stopping: &AtomicBool,
+    options: ServerOptions,
+) {
+    let (Ok(reader), Ok(handle)) = (stream.try_clone(), stream.try_clone()) else {
+        return;
+    };
+    let mut stream = stream;
+
+    let _ = stream.set_read_timeout(Some(SUBSCRIPTION_TIMEOUT));
+    let mut line = String::new();
+    let subscription = match BufReader::new(reader).read_line(&mut line) {
+        Ok(_) => Subscription::parse(&line).and_then(|subscription| {
+            subscription
+                .filter(root)
+                .map(|filter| (subscription, filter))
+        }),
+        Err(e) => Err(format!("No subscription received: {}", e)),
+    };
+    let (subscription, filter) = match subscription {
+        Ok(subscription) => subscription,
+        Err(message) => {
+            debug!(error = %message, "Client rejected");
+            let _ = writeln!(stream, "{}", serde_json::json!({ "error": message }));
+            return;
+        }
+    };
+    let _ = stream.set_read_timeout(None);
+
+    let (tx, rx): (Sender<Arc<str>>, Receiver<Arc<str>>) = bounded(options.client_buffer.max(1));
+    {
+        let mut clients = clients.lock().unwrap();
+        // The server clears the clients once stopping, under the same lock
+        if stopping.load(Ordering::SeqCst) {
+            return;
+        }
+        clients.next_id += 1;
+        let id = clients.next_id;
+        debug!(client = id, ?subscription, "Client subscribed");
+        clients.clients.push(Client {
+            id,
+            filter,
+            tx,
+            stream: handle,
+            behind: false,
+        });
+    }
+
+    for line in rx {
+        if stream.write_all(line.as_bytes()).is_err() {
+            // Dropping the receiver lets the next broadcast remove the client
+            return;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            // A socket still accepting connections belongs to a running server
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("{} is used by another server", path.display()),
+                ));
+            }
+            fs::remove_file(path)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use notify::event::{CreateKind, ModifyKind};
+    use notify::EventKind;
+
+    #[test]
+    fn test_parse_addresses_and_subscriptions() {
+        assert_eq!(
+            "unix:/run/watchcrab.sock".parse(),
+            Ok(ServerAddr::Unix(PathBuf::from("/run/watchcrab.sock")))
+        );
+        assert_eq!(
+            "tcp:0.0.0.0:7070".parse(),
+            Ok(ServerAddr::Tcp(String::from("0.0.0.0:7070")))
+        );
+        assert_eq!(
+            "[::1]:7070".parse(),
+            Ok(ServerAddr::Tcp(String::from("[::1]:7070")))
+        );
+        assert!("localhost".parse::<ServerAddr>().is_err());
+
+        assert_eq!(Subscription::parse(""), Ok(Subscription::default()));
+        assert_eq!(
+            Subscription::parse(r#"{"include": ["*.md"], "kinds": ["create"]}"#),
+            Ok(Subscription {
+                include: vec![String::from("*.md")],
+                kinds: vec![String::from("create")],
+                ..Subscription::default()
+            })
+        );
+        assert!(Subscription::parse(r#"{"paths": ["*.md"]}"#).is_err());
+        assert!(Subscription::parse(r#"{"include": ["[md"]}"#).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_broadcast_to_subscribed_clients() {
+        let dir = TempDir::new("server");
+        let socket = dir.join("watchcrab.sock");
+        let server = Server::bind(
+            &[ServerAddr::Unix(socket.clone())],
+            Path::new("/project"),
+            ServerOptions::default(),
+        )
+        .unwrap();
+
+        let addr = ServerAddr::Unix(socket.clone());
+        let subscribe = |include: &[&str], exclude: &[&str], kinds: &[&str]| {
+            let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+            let subscription = Subscription {
+                include: strings(include),
+                exclude: strings(exclude),
+                kinds: strings(kinds),
+            };
+            Subscriber::connect(&addr, &subscription).unwrap()
+        };
+        let mut all = subscribe(&[], &[], &[]);
+        let mut docs = subscribe(&["docs/**"], &["*.tmp"], &["modify"]);
+        let mut invalid = subscribe(&["[md"], &[], &[]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.clients() < 2 {
+            assert!(Instant::now() < deadline, "The clients did not subscribe");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let event = |kind, path: &str| Event::new(kind).add_path(PathBuf::from(path));
+        let modify = EventKind::Modify(ModifyKind::Any);
+        server.broadcast(&event(
+            EventKind::Create(CreateKind::File),
+            "/project/docs/a.md",
+        ));
+        server.broadcast(&event(modify, "/project/docs/b.tmp"));
+        server.broadcast(&event(modify, "/project/docs/c.md"));
+        drop(server);
+
+        let paths = |subscriber: &mut Subscriber| subscriber.next().unwrap().unwrap().paths;
+        assert_eq!(paths(&mut all), vec![PathBuf::from("/project/docs/a.md")]);
+        assert_eq!(paths(&mut all), vec![PathBuf::from("/project/docs/b.tmp")]);
+        let c = docs.next().unwrap().unwrap();
+        assert_eq!(
+            (c.kind.as_str(), c.detail.as_str()),
+            ("modify", "Modify(Any)")
+        );
+        assert_eq!(c.paths, vec![PathBuf::from("/project/docs/c.md")]);
+        let rejection = invalid.next().unwrap().unwrap_err();
+        assert_eq!(rejection.kind(), ErrorKind::InvalidInput);
+        assert!(invalid.next().is_none());
+
+        assert!(!socket.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_drop_closes_the_connections_and_joins_the_threads() {
+        let dir = TempDir::new("server-drop");
+        let socket = dir.join("watchcrab.sock");
+        let options = ServerOptions {
+            client_buffer: 100_000,
+            slow_client: SlowClientPolicy::Drop,
+        };
+        let server = Server::bind(
+            &[ServerAddr::Unix(socket.clone())],
+            Path::new("/project"),
+            options,
+        )
+        .unwrap();
+        let addr = ServerAddr::Unix(socket.clone());
+        // A client that never reads its events, its writer thread blocks once the socket buffer is full
+        let _slow = Subscriber::connect(&addr, &Subscription::default()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.clients() < 1 {
+            assert!(Instant::now() < deadline, "The client did not subscribe");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let event = Event::new(EventKind::Modify(ModifyKind::Any))
+            .add_path(PathBuf::from(format!("/project/{}", "a".repeat(200))));
+        for _ in 0..50_000 {
+            server.broadcast(&event);
+        }
+
+        let start = Instant::now();
+        drop(server);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(Subscriber::connect(&addr, &Subscription::default()).is_err());
+    }
+}
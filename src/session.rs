@@ -0,0 +1,444 @@
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{after, bounded, never, select, tick, Receiver, RecvTimeoutError, Sender};
+use notify::{Event, RecursiveMode, Watcher};
+use tracing::{debug, error, info, warn};
+
+#[cfg(feature = "testing")]
+use crate::backend::open_fake_watcher;
+use crate::backend::{open_watcher, Backend, PollOptions};
+use crate::event::kind_name;
+use crate::queue::{event_queue, OverflowPolicy, QueueStats};
+use crate::scan::{self, Snapshot};
+use crate::state::{self, HandledState};
+#[cfg(feature = "testing")]
+use crate::testing::FakeBackend;
+
+/// Interval between two reports of the events dropped or coalesced by the event queue
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Owned settings of the source of the events of a watch
+#[derive(Debug, Clone)]
+pub(crate) struct SourceConfig {
+    pub roots: Vec<(PathBuf, Backend)>,
+    pub recursive: bool,
+    pub poll: PollOptions,
+    pub initial_scan: bool,
+    pub state_file: Option<PathBuf>,
+    pub state_interval: Duration,
+    pub hash_contents: bool,
+    pub rescan_on_overflow: bool,
+    pub queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    #[cfg(feature = "testing")]
+    pub fake_backend: Option<FakeBackend>,
+}
+
+/// Running watchers of a watch and the state needed to turn their raw events into the events to handle
+///
+/// This is the part of a watch shared by `Watch::start` and the other ways to consume events:
+/// the event queue, the startup events (state file or initial scan), the rescans on overflow and the state of the handled events saved to the state file.
+pub(crate) struct Session {
+    config: SourceConfig,
+    watchers: Vec<(PathBuf, Box<dyn Watcher + Send>)>,
+    roots: Vec<PathBuf>,
+    // The state file and its temporary file, their changes are not events of the watch
+    own_files: Vec<PathBuf>,
+    rx: Receiver<Result<Event, notify::Error>>,
+    queue_stats: Arc<QueueStats>,
+    // Snapshot used to recover the events lost on queue overflow
+    tree: Option<Snapshot>,
+    reported_stats: (u64, u64),
+    handled: Option<HandledState>,
+    // Thread saving the handled state periodically, stopped by dropping the sender
+    state_saver: Option<(Sender<()>, JoinHandle<()>)>,
+    stats_ticker: Receiver<Instant>,
+}
+
+impl Session {
+    /// Create the event queue and register a watcher on every root
+    ///
+    /// # Errors
+    /// Returns an `Error` if a root does not exist or a watcher can't be registered
+    pub fn open(config: SourceConfig) -> Result<Session, Error> {
+        let (tx, rx) = event_queue(config.queue_capacity, config.overflow_policy);
+        let queue_stats = tx.stats();
+
+        let recursive_mode = if config.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+
+        // One watcher per root, so each root can use its own backend
+        let mut watchers: Vec<(PathBuf, Box<dyn Watcher + Send>)> = Vec::new();
+        for (path, backend) in &config.roots {
+            // The fake backend watches the roots as given, they don't have to exist
+            #[cfg(feature = "testing")]
+            if let Some(fake_backend) = &config.fake_backend {
+                let root = path.clone();
+                let watcher = open_fake_watcher(&root, recursive_mode, fake_backend, tx.clone())
+                    .map_err(Error::other)?;
+                info!(root = %root.display(), backend = "fake", recursive = config.recursive, "Watching");
+                watchers.push((root, watcher));
+                continue;
+            }
+            let root = path.canonicalize()?;
+            let watcher = open_watcher(&root, recursive_mode, *backend, config.poll, tx.clone())
+                .map_err(Error::other)?;
+            info!(root = %root.display(), %backend, recursive = config.recursive, "Watching");
+            watchers.push((root, watcher));
+        }
+        drop(tx); // The watchers own the remaining senders
+        let roots = watchers.iter().map(|(root, _)| root.clone()).collect();
+        let own_files = match &config.state_file {
+            Some(state_file) => state_files(state_file)?,
+            None => Vec::new(),
+        };
+
+        Ok(Session {
+            config,
+            watchers,
+            roots,
+            own_files,
+            rx,
+            queue_stats,
+            tree: None,
+            reported_stats: (0, 0),
+            handled: None,
+            state_saver: None,
+            stats_ticker: tick(STATS_INTERVAL),
+        })
+    }
+
+    /// Events for the changes made before the watchers were registered
+    ///
+    /// With a saved state, the events turning the saved state into the current tree; otherwise, with the initial scan, a `create` event per existing file.
+    /// Also starts saving the state of the handled events, see `Session::handled`, and takes the snapshot used to rescan on overflow.
+    ///
+    /// # Errors
+    /// Returns an `Error` if the state file or a root can't be read
+    pub fn startup(&mut self) -> Result<Vec<Event>, Error> {
+        let saved_state = match &self.config.state_file {
+            Some(state_file) => state::load(state_file)?,
+            None => None,
+        };
+
+        let mut events = Vec::new();
+        // State of the files once the startup events are handled, before that the saved state stays the reference
+        let mut handled = None;
+        if let Some(saved_state) = saved_state {
+            // Catch up on the changes made while the watcher was stopped
+            let current = self.snapshot()?;
+            events = saved_state.diff(&current);
+            handled = Some(saved_state);
+        } else if self.config.initial_scan {
+            for root in &self.roots {
+                for file in scan::walk(root, self.config.recursive)? {
+                    events.push(scan::synthetic_create(file));
+                }
+            }
+            handled = Some(Snapshot::default());
+        }
+        if self.config.state_file.is_some() {
+            let handled = match handled {
+                Some(handled) => handled,
+                None => self.snapshot()?,
+            };
+            self.start_state(handled);
+        }
+
+        if self.config.rescan_on_overflow {
+            self.tree = Some(self.snapshot()?);
+        }
+
+        events.retain(|event| !self.is_own_event(event));
+        Ok(events)
+    }
+
+    /// Raw events sent by the watchers, before rescans and filters
+    pub fn receiver(&self) -> &Receiver<Result<Event, notify::Error>> {
+        &self.rx
+    }
+
+    /// Wait for the next raw event until the deadline, saving the state file and reporting the queue counters meanwhile
+    ///
+    /// # Errors
+    /// Returns `RecvTimeoutError::Timeout` once the deadline is reached and `RecvTimeoutError::Disconnected` if every watcher stopped
+    pub fn recv_deadline(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<Result<Event, notify::Error>, RecvTimeoutError> {
+        let timeout = match deadline {
+            Some(deadline) => after(deadline.saturating_duration_since(Instant::now())),
+            None => never(),
+        };
+        loop {
+            select! {
+                recv(self.rx) -> event_result => {
+                    return event_result.map_err(|_| RecvTimeoutError::Disconnected);
+                }
+                recv(timeout) -> _ => return Err(RecvTimeoutError::Timeout),
+                recv(self.stats_ticker) -> _ => self.report_queue_stats(),
+            }
+        }
+    }
+
+    /// Turn an event of the queue into the events to handle, rescanning the affected root if the backend missed events
+    pub fn handle(
+        &mut self,
+        event_result: Result<Event, notify::Error>,
+    ) -> Vec<Result<Event, notify::Error>> {
+        if let Ok(event) = &event_result {
+            // Saving the state file would otherwise trigger events, and the next save, forever
+            if self.is_own_event(event) {
+                return Vec::new();
+            }
+            debug!(kind = kind_name(event), paths = ?event.paths, "Event received");
+            if event.need_rescan() {
+                return match event.paths.first() {
+                    Some(root) => self.rescan(&root.clone()),
+                    None => Vec::new(),
+                };
+            }
+            if let Some(tree) = &mut self.tree {
+                for path in &event.paths {
+                    tree.update_path(path, self.config.hash_contents);
+                }
+            }
+        }
+        vec![event_result]
+    }
+
+    /// State of the handled events when a state file is configured, to be updated once an event was handled
+    pub fn handled_state(&self) -> Option<HandledState> {
+        self.handled.clone()
+    }
+
+    /// Record that an event was handled, for the events consumed without a `HandledState`
+    pub fn handled(&self, event: &Event) {
+        if let Some(handled) = &self.handled {
+            handled.handled(&event.paths);
+        }
+    }
+
+    /// Dispatch the events until `stop_rx` receives a message or is disconnected, or `dispatch` returns false
+    ///
+    /// On stop, the watchers are stopped and the events already queued are dispatched.
+    /// The queue counters are reported while running.
+    pub fn run<F>(&mut self, stop_rx: &Receiver<()>, mut dispatch: F)
+    where
+        F: FnMut(Result<Event, notify::Error>) -> bool,
+    {
+        loop {
+            select! {
+                recv(self.rx) -> event_result => {
+                    match event_result {
+                        Ok(event_result) => {
+                            for event_result in self.handle(event_result) {
+                                if !dispatch(event_result) {
+                                    return;
+                                }
+                            }
+                        }
+                        Err(_) => break, // Closed channel, exit the loop
+                    }
+                }
+                recv(stop_rx) -> _ => {
+                    self.stop_watchers();
+                    // Process pending events
+                    while let Ok(event_result) = self.rx.try_recv() {
+                        for event_result in self.handle(event_result) {
+                            if !dispatch(event_result) {
+                                return;
+                            }
+                        }
+                    }
+                    break;
+                }
+                recv(self.stats_ticker) -> _ => self.report_queue_stats(),
+            }
+        }
+    }
+
+    /// Save the state file and report the last queue counters, to be called once every event was handled
+    pub fn close(&mut self) {
+        if let Some((stop_tx, saver)) = self.state_saver.take() {
+            drop(stop_tx);
+            let _ = saver.join();
+        }
+        self.save_state();
+        self.report_queue_stats();
+    }
+
+    /// Stop the watchers by dropping them
+    ///
+    /// Unlike `Watcher::unwatch`, dropping a watcher does not wait for its event loop, which may be blocked sending
+    /// to a full queue with `OverflowPolicy::Block` until the pending events are drained.
+    fn stop_watchers(&mut self) {
+        self.watchers.clear();
+    }
+
+    /// Whether an event only concerns the state file of the session
+    fn is_own_event(&self, event: &Event) -> bool {
+        !event.paths.is_empty() && event.paths.iter().all(|path| self.own_files.contains(path))
+    }
+
+    fn snapshot(&self) -> Result<Snapshot, Error> {
+        Snapshot::take(
+            &self.roots,
+            self.config.recursive,
+            self.config.hash_contents,
+        )
+    }
+
+    /// Notify the handlers that a root is rescanned and synthesize the differences with the cached snapshot
+    fn rescan(&mut self, root: &Path) -> Vec<Result<Event, notify::Error>> {
+        warn!(root = %root.display(), "Events were missed (event queue overflow), rescanning");
+        let mut events = vec![Ok(scan::rescan_event(root.to_path_buf()))];
+
+        let Some(tree) = &mut self.tree else {
+            warn!("Rescan on overflow is disabled, the missed events are lost");
+            return events;
+        };
+        match tree.rescan(root, self.config.recursive, self.config.hash_contents) {
+            Ok(diff) => events.extend(
+                diff.into_iter()
+                    .filter(|event| !self.is_own_event(event))
+                    .map(Ok),
+            ),
+            Err(e) => error!(root = %root.display(), error = %e, "Unable to rescan"),
+        }
+        events
+    }
+
+    /// Track the handled events from `snapshot`, save it and keep saving it periodically from another thread
+    fn start_state(&mut self, snapshot: Snapshot) {
+        let Some(state_file) = self.config.state_file.clone() else {
+            return;
+        };
+        let handled = HandledState::new(snapshot, self.config.hash_contents);
+        save_state(&handled, &state_file);
+
+        let (stop_tx, stop_rx) = bounded::<()>(0);
+        let ticker = tick(self.config.state_interval);
+        let saved = handled.clone();
+        let saver = thread::spawn(move || loop {
+            select! {
+                recv(ticker) -> _ => save_state(&saved, &state_file),
+                recv(stop_rx) -> _ => break,
+            }
+        });
+        self.handled = Some(handled);
+        self.state_saver = Some((stop_tx, saver));
+    }
+
+    /// Save the handled state if a state file is configured
+    fn save_state(&self) {
+        if let (Some(handled), Some(state_file)) = (&self.handled, &self.config.state_file) {
+            save_state(handled, state_file);
+        }
+    }
+
+    /// Log the number of events dropped or coalesced by the event queue since the last report
+    fn report_queue_stats(&mut self) {
+        let current = (self.queue_stats.dropped(), self.queue_stats.coalesced());
+        let reported = self.reported_stats;
+        if current != reported {
+            warn!(
+                dropped = current.0 - reported.0,
+                coalesced = current.1 - reported.1,
+                total_dropped = current.0,
+                total_coalesced = current.1,
+                "Event queue full, events were dropped or coalesced since the last report"
+            );
+            self.reported_stats = current;
+        }
+    }
+}
+
+/// Save the handled state, errors are reported without stopping the watcher
+/// Absolute paths of a state file and its temporary file, as reported by the watchers
+fn state_files(state_file: &Path) -> Result<Vec<PathBuf>, Error> {
+    let (Some(dir), Some(name)) = (state_file.parent(), state_file.file_name()) else {
+        return Ok(Vec::new());
+    };
+    // The state file may not exist yet, its directory does
+    let dir = match dir.as_os_str().is_empty() {
+        true => Path::new(".").canonicalize()?,
+        false => dir.canonicalize()?,
+    };
+    let mut tmp_name = name.to_owned();
+    tmp_name.push(".tmp");
+    Ok(vec![dir.join(name), dir.join(tmp_name)])
+}
+
+fn save_state(handled: &HandledState, state_file: &Path) {
+    if let Err(e) = handled.save(state_file) {
+        error!(state_file = %state_file.display(), error = %e, "Unable to save state file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::watch::Watch;
+
+    #[test]
+    fn test_stop_with_a_full_blocking_queue() {
+        let dir = TempDir::new("session-full-queue");
+        let events = vec!["all".to_string()];
+        let config = Watch::source(&dir, false, &events)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Block)
+            .source_config();
+        let mut session = Session::open(config).unwrap();
+        for i in 0..100 {
+            std::fs::write(dir.join(i.to_string()), "content").unwrap();
+        }
+        thread::sleep(Duration::from_millis(200)); // Let the event loop block on the full queue
+
+        let (stop_tx, stop_rx) = bounded(1);
+        let (done_tx, done_rx) = bounded(1);
+        stop_tx.send(()).unwrap();
+        thread::spawn(move || {
+            session.run(&stop_rx, |_| true);
+            let _ = done_tx.send(());
+        });
+        assert!(done_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn test_state_file_inside_a_root_is_not_watched() {
+        let dir = TempDir::new("session-own-state-file");
+        let state_file = dir.join("state");
+        let events = vec!["all".to_string()];
+        let config = Watch::source(&dir, false, &events)
+            .state_file(&state_file)
+            .state_interval(Duration::from_millis(20))
+            .source_config();
+        let mut session = Session::open(config).unwrap();
+        assert!(session.startup().unwrap().is_empty());
+        std::fs::write(dir.join("a.txt"), "content").unwrap();
+
+        let mut paths = Vec::new();
+        let deadline = Instant::now() + Duration::from_millis(500);
+        while let Ok(event_result) = session.recv_deadline(Some(deadline)) {
+            for event in session.handle(event_result).into_iter().flatten() {
+                session.handled(&event);
+                paths.extend(event.paths);
+            }
+        }
+        session.close();
+
+        assert!(paths.iter().any(|path| path.ends_with("a.txt")));
+        assert!(paths
+            .iter()
+            .all(|path| !path.ends_with("state") && !path.ends_with("state.tmp")));
+    }
+}
@@ -0,0 +1,308 @@
+use std::io::{self, Error, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, never, select, tick, Receiver, Sender};
+use tracing::error;
+
+use crate::journald::Journald;
+use crate::rotate::{RotatingWriter, RotationOptions};
+use crate::syslog::Syslog;
+use crate::webhook::Webhook;
+
+/// Default number of records written by the writer thread between two flushes
+pub const DEFAULT_BATCH_SIZE: usize = 256;
+
+/// Default interval between two syncs of the output files to the disk
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Destination of the records of a `LogWriter`
+pub enum Sink {
+    Stdout,
+    File(RotatingWriter),
+    Webhook(Webhook),
+    Syslog(Syslog),
+    Journald(Journald),
+}
+
+impl Sink {
+    /// Sink appending to a file, rotated according to `rotation`
+    ///
+    /// # Errors
+    /// Returns an `Error` if the file can't be opened
+    pub fn file(path: &Path, rotation: RotationOptions) -> Result<Sink, Error> {
+        Ok(Sink::File(RotatingWriter::open(path, rotation)?))
+    }
+
+    fn write_batch(&mut self, records: &[String]) -> Result<(), Error> {
+        match self {
+            Sink::Stdout => {
+                let mut stdout = io::stdout().lock();
+                for record in records {
+                    writeln!(stdout, "{}", record)?;
+                }
+                stdout.flush()
+            }
+            Sink::File(writer) => {
+                for record in records {
+                    writer.write_line(record)?;
+                }
+                writer.flush()
+            }
+            Sink::Webhook(webhook) => {
+                webhook.send(records.to_vec());
+                Ok(())
+            }
+            Sink::Syslog(syslog) => records.iter().try_for_each(|record| syslog.send(record)),
+            Sink::Journald(journald) => records.iter().try_for_each(|record| journald.send(record)),
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        match self {
+            Sink::Stdout => io::stdout().flush(),
+            Sink::File(writer) => writer.sync(),
+            // The delivery thread of the webhook sends the records as soon as it can
+            Sink::Webhook(_) => Ok(()),
+            // Datagrams are handed to the daemon as they are sent
+            Sink::Syslog(_) | Sink::Journald(_) => Ok(()),
+        }
+    }
+
+    /// Wait for the records written to be delivered, for the sinks delivering them in the background
+    fn wait(&mut self) {
+        if let Sink::Webhook(webhook) = self {
+            webhook.flush();
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Sink::Stdout => "stdout",
+            Sink::File(_) => "file",
+            Sink::Webhook(_) => "webhook",
+            Sink::Syslog(_) => "syslog",
+            Sink::Journald(_) => "journald",
+        }
+    }
+}
+
+/// Options of the writer thread of a `LogWriter`
+///
+/// * `capacity` - Number of records waiting to be written before `LogWriter::write` blocks
+/// * `batch_size` - Maximum number of records written between two flushes
+/// * `sync_interval` - Interval between two syncs of the files to the disk, `None` to leave it to the OS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriterOptions {
+    pub capacity: usize,
+    pub batch_size: usize,
+    pub sync_interval: Option<Duration>,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        WriterOptions {
+            capacity: 4096,
+            batch_size: DEFAULT_BATCH_SIZE,
+            sync_interval: Some(DEFAULT_SYNC_INTERVAL),
+        }
+    }
+}
+
+enum Message {
+    Record(String),
+    Flush(Sender<()>),
+}
+
+/// Handle to a dedicated thread writing records to one or more sinks
+///
+/// Handlers running in several threads send their records through a channel instead of sharing a lock, the writer thread
+/// writes them in batches, in the order they were received, to every sink. The handle is cheap to clone.
+/// The records still queued are written when the last handle is dropped, call `LogWriter::flush` to wait for them before exiting the process.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// use watchcrab::rotate::RotationOptions;
+/// use watchcrab::sink::{LogWriter, Sink, WriterOptions};
+///
+/// let sinks = vec![
+///     Sink::Stdout,
+///     Sink::file(Path::new("events.log"), RotationOptions::default()).unwrap(),
+/// ];
+/// let writer = LogWriter::spawn(sinks, WriterOptions::default());
+/// writer.write(String::from(r#"{"Kind": "Create(File)", "Path": "/tmp/new.txt"}"#));
+/// writer.flush();
+/// ```
+#[derive(Clone)]
+pub struct LogWriter {
+    tx: Sender<Message>,
+}
+
+impl LogWriter {
+    /// Start the writer thread
+    pub fn spawn(sinks: Vec<Sink>, options: WriterOptions) -> LogWriter {
+        let (tx, rx) = bounded(options.capacity.max(1));
+        thread::spawn(move || run(rx, sinks, options));
+        LogWriter { tx }
+    }
+
+    /// Queue a record, waiting for room if the writer thread falls behind
+    pub fn write(&self, record: String) {
+        let _ = self.tx.send(Message::Record(record));
+    }
+
+    /// Wait until the records queued before are written and synced to the disk, or delivered by the webhooks
+    pub fn flush(&self) {
+        let (ack_tx, ack_rx) = bounded(1);
+        if self.tx.send(Message::Flush(ack_tx)).is_ok() {
+            let _ = ack_rx.recv();
+        }
+    }
+}
+
+fn run(rx: Receiver<Message>, mut sinks: Vec<Sink>, options: WriterOptions) {
+    let sync_ticker = match options.sync_interval {
+        Some(interval) => tick(interval),
+        None => never(),
+    };
+    let batch_size = options.batch_size.max(1);
+    let mut unsynced = false;
+    let mut batch: Vec<String> = Vec::with_capacity(batch_size);
+
+    loop {
+        select! {
+            recv(rx) -> message => {
+                let Ok(message) = message else {
+                    // Every handle was dropped
+                    sync_all(&mut sinks);
+                    return;
+                };
+                let mut next = Some(message);
+                while let Some(message) = next.take() {
+                    match message {
+                        Message::Record(record) => batch.push(record),
+                        Message::Flush(ack) => {
+                            write_all(&mut sinks, &mut batch);
+                            sync_all(&mut sinks);
+                            sinks.iter_mut().for_each(Sink::wait);
+                            unsynced = false;
+                            let _ = ack.send(());
+                        }
+                    }
+                    if batch.len() < batch_size {
+                        next = rx.try_recv().ok();
+                    }
+                }
+                if !batch.is_empty() {
+                    write_all(&mut sinks, &mut batch);
+                    unsynced = true;
+                }
+            }
+            recv(sync_ticker) -> _ => {
+                if unsynced {
+                    sync_all(&mut sinks);
+                    unsynced = false;
+                }
+            }
+        }
+    }
+}
+
+fn write_all(sinks: &mut [Sink], batch: &mut Vec<String>) {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.write_batch(batch) {
+            error!(sink = sink.name(), error = %e, "Couldn't write records");
+        }
+    }
+    batch.clear();
+}
+
+fn sync_all(sinks: &mut [Sink]) {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.sync() {
+            error!(sink = sink.name(), error = %e, "Couldn't sync records");
+        }
+    }
+}
+
+/// JSON record of string fields, in order, with the layout of the records of the command line tool
+///
+/// # Examples
+///
+/// ```
+/// use watchcrab::sink::json_record;
+///
+/// let record = json_record(&[("stdout", "say \"hi\"\nbye"), ("stderr", "")]);
+/// assert_eq!(record, r#"{"stdout": "say \"hi\"\nbye", "stderr": ""}"#);
+/// ```
+pub fn json_record(fields: &[(&str, &str)]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}: {}",
+                serde_json::Value::from(*name),
+                serde_json::Value::from(*value)
+            )
+        })
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+/// Top-level fields of a JSON record, strings as is and other values as JSON, empty if the record is not a JSON object
+pub(crate) fn record_fields(record: &str) -> Vec<(String, String)> {
+    match serde_json::from_str::<serde_json::Value>(record) {
+        Ok(serde_json::Value::Object(fields)) => fields
+            .into_iter()
+            .map(|(name, value)| match value {
+                serde_json::Value::String(value) => (name, value),
+                value => (name, value.to_string()),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_records_reach_every_sink_in_order() {
+        let dir = TempDir::new("sink");
+        let paths = [dir.join("a.log"), dir.join("b.log")];
+
+        let sinks = paths
+            .iter()
+            .map(|path| Sink::file(path, RotationOptions::default()).unwrap())
+            .collect();
+        let writer = LogWriter::spawn(sinks, WriterOptions::default());
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let writer = writer.clone();
+                thread::spawn(move || {
+                    for record in 0..100 {
+                        writer.write(format!("{} {}", thread, record));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        writer.flush();
+
+        for path in &paths {
+            let content = fs::read_to_string(path).unwrap();
+            assert_eq!(content.lines().count(), 400);
+            let thread_0: Vec<&str> = content.lines().filter(|l| l.starts_with("0 ")).collect();
+            assert_eq!(thread_0.first(), Some(&"0 0"));
+            assert_eq!(thread_0.last(), Some(&"0 99"));
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::scan::{FileMeta, Snapshot};
+
+const HEADER: &str = "# watchcrab state v1";
+
+/// Load a snapshot from a state file
+///
+/// The state file starts with a `# watchcrab state v1` header followed by one line per file:
+/// `<size>\t<mtime>\t<inode>\t<hash or ->\t<path>`, with `\` and newlines in the path escaped, and the bytes that are not valid UTF-8 escaped as `\xHH`.
+///
+/// # Returns
+/// `Ok(None)` if the state file does not exist
+///
+/// # Errors
+/// Returns an `Error` if the state file can't be read or is malformed
+pub fn load(state_file: &Path) -> Result<Option<Snapshot>, Error> {
+    let content = match fs::read_to_string(state_file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    // Split on `\n` only, a path may end with a `\r`
+    let mut lines = content.strip_suffix('\n').unwrap_or(&content).split('\n');
+    if lines.next() != Some(HEADER) {
+        return Err(invalid(state_file, "missing header"));
+    }
+
+    let mut snapshot = Snapshot::default();
+    for line in lines {
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        if fields.len() != 5 {
+            return Err(invalid(state_file, line));
+        }
+        let meta = FileMeta {
+            size: fields[0].parse().map_err(|_| invalid(state_file, line))?,
+            mtime: fields[1].parse().map_err(|_| invalid(state_file, line))?,
+            inode: fields[2].parse().map_err(|_| invalid(state_file, line))?,
+            hash: match fields[3] {
+                "-" => None,
+                hash => Some(u64::from_str_radix(hash, 16).map_err(|_| invalid(state_file, line))?),
+            },
+        };
+        snapshot.entries.insert(unescape(fields[4]), meta);
+    }
+    Ok(Some(snapshot))
+}
+
+/// Save a snapshot to a state file
+///
+/// The snapshot is written to a temporary file next to the state file and then renamed, so a crash while saving never leaves a truncated state file.
+///
+/// # Errors
+/// Returns an `Error` if the state file can't be written
+pub fn save(state_file: &Path, snapshot: &Snapshot) -> Result<(), Error> {
+    let mut tmp_name = state_file.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let mut writer = BufWriter::new(fs::File::create(&tmp_path)?);
+    writeln!(writer, "{}", HEADER)?;
+    for (path, meta) in &snapshot.entries {
+        let hash = match meta.hash {
+            Some(hash) => format!("{:016x}", hash),
+            None => String::from("-"),
+        };
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            meta.size,
+            meta.mtime,
+            meta.inode,
+            hash,
+            escape(path)
+        )?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+
+    fs::rename(&tmp_path, state_file)
+}
+
+fn invalid(state_file: &Path, line: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid state file {}: {}", state_file.display(), line),
+    )
+}
+
+/// Bytes of a path, lossy outside of Unix where paths are Unicode
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        match path.to_string_lossy() {
+            Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+            Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+        }
+    }
+}
+
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+fn escape(path: &Path) -> String {
+    let bytes = path_bytes(path);
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            let _ = write!(escaped, "\\x{:02x}", byte);
+        }
+    }
+    escaped
+}
+
+fn unescape(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            unescaped.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes[i + 1] {
+            b'n' => unescaped.push(b'\n'),
+            b'x' => {
+                let byte = path
+                    .get(i + 2..i + 4)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(byte) = byte {
+                    unescaped.push(byte);
+                    i += 4;
+                    continue;
+                }
+                unescaped.push(b'x');
+            }
+            other => unescaped.push(other),
+        }
+        i += 2;
+    }
+    path_from_bytes(unescaped)
+}
+
+/// Snapshot of the files as of their last handled event, saved to the state file
+///
+/// It is updated once an event was handled rather than when it was received, so the events still queued, in the thread pool
+/// or in a layer when watchcrab stops are caught up on at the next start.
+#[derive(Debug, Clone)]
+pub(crate) struct HandledState {
+    snapshot: Arc<Mutex<Snapshot>>,
+    hash_contents: bool,
+}
+
+impl HandledState {
+    pub fn new(snapshot: Snapshot, hash_contents: bool) -> HandledState {
+        HandledState {
+            snapshot: Arc::new(Mutex::new(snapshot)),
+            hash_contents,
+        }
+    }
+
+    /// Record the current state of the paths of a handled event
+    pub fn handled(&self, paths: &[PathBuf]) {
+        for path in paths {
+            // The file is read, and hashed, outside of the lock
+            match fs::metadata(path) {
+                Ok(metadata) if metadata.is_dir() => (),
+                Ok(_) => {
+                    if let Ok(meta) = FileMeta::read(path, self.hash_contents) {
+                        self.snapshot
+                            .lock()
+                            .unwrap()
+                            .entries
+                            .insert(path.clone(), meta);
+                    }
+                }
+                Err(_) => {
+                    self.snapshot.lock().unwrap().remove_tree(path);
+                }
+            }
+        }
+    }
+
+    /// Save the handled state to a state file, see `save`
+    ///
+    /// # Errors
+    /// Returns an `Error` if the state file can't be written
+    pub fn save(&self, state_file: &Path) -> Result<(), Error> {
+        let snapshot = self.snapshot.lock().unwrap().clone();
+        save(state_file, &snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_save_and_load_roundtrip() {
+        let dir = TempDir::new("state");
+        let state_file = dir.join("state");
+
+        let mut snapshot = Snapshot::default();
+        snapshot.entries.insert(
+            PathBuf::from("/tmp/a\tb\\c\nd"),
+            FileMeta {
+                size: 10,
+                mtime: 1_700_000_000_123_456_789,
+                inode: 42,
+                hash: Some(0xdeadbeef),
+            },
+        );
+        snapshot.entries.insert(
+            PathBuf::from("/tmp/carriage return\r"),
+            FileMeta {
+                size: 2,
+                mtime: 2,
+                inode: 2,
+                hash: None,
+            },
+        );
+        snapshot.entries.insert(
+            PathBuf::from("/tmp/plain"),
+            FileMeta {
+                size: 0,
+                mtime: 0,
+                inode: 0,
+                hash: None,
+            },
+        );
+
+        // Not valid UTF-8, followed by a literal `\xff`
+        #[cfg(unix)]
+        snapshot.entries.insert(
+            path_from_bytes(b"/tmp/\xff\\xff".to_vec()),
+            FileMeta {
+                size: 1,
+                mtime: 1,
+                inode: 1,
+                hash: None,
+            },
+        );
+
+        save(&state_file, &snapshot).unwrap();
+        assert_eq!(load(&state_file).unwrap(), Some(snapshot));
+
+        fs::remove_file(&state_file).unwrap();
+        assert_eq!(load(&state_file).unwrap(), None);
+    }
+
+    #[test]
+    fn test_only_handled_events_advance_the_state() {
+        let dir = TempDir::new("handled");
+        let (handled_file, queued_file) = (dir.join("handled"), dir.join("queued"));
+        fs::write(&handled_file, "").unwrap();
+        fs::write(&queued_file, "").unwrap();
+
+        let handled = HandledState::new(Snapshot::default(), false);
+        handled.handled(&[handled_file.clone(), dir.to_path_buf()]);
+        let state_file = dir.join("state");
+        handled.save(&state_file).unwrap();
+        let saved = load(&state_file).unwrap().unwrap();
+        assert_eq!(
+            saved.entries.keys().collect::<Vec<_>>(),
+            vec![&handled_file]
+        );
+
+        fs::remove_file(&handled_file).unwrap();
+        handled.handled(&[handled_file]);
+        handled.save(&state_file).unwrap();
+        assert!(load(&state_file).unwrap().unwrap().entries.is_empty());
+    }
+}
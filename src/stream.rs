@@ -0,0 +1,210 @@
+use std::future::Future;
+use std::io::Error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{bounded, Sender};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{SinkExt, Stream, StreamExt};
+use tracing::error;
+
+use crate::event::{matches_filter, WatchEvent};
+use crate::queue::DEFAULT_QUEUE_CAPACITY;
+use crate::session::Session;
+use crate::watch::Watch;
+
+/// Stream of the filtered events of a watch, for async runtimes
+///
+/// The watchers run in a dedicated thread that forwards the events passing the events filter, with the same startup events,
+/// rescans and state file handling as `Watch::start`. The stream applies backpressure: when the consumer falls behind,
+/// events wait in the event queue and its overflow policy applies.
+///
+/// Dropping the stream stops the watchers and waits for the state file to be saved. No signal handler is installed, the runtime stays in charge of the shutdown.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// use futures::StreamExt;
+/// use watchcrab::watch::Watch;
+///
+/// # futures::executor::block_on(async {
+/// let events = vec!["create".to_string()];
+/// let mut stream = Watch::source(Path::new("./"), true, &events).stream().unwrap();
+///
+/// while let Some(event) = stream.next().await {
+///     println!("{:?}", event);
+/// }
+/// # });
+/// ```
+pub struct EventStream {
+    rx: mpsc::Receiver<Result<WatchEvent, Error>>,
+    // Dropping the sender stops the session thread
+    stop_tx: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watch<'_> {
+    /// Start the watchers and return the stream of the filtered events instead of calling the handler
+    ///
+    /// The handler and the number of threads of the watch are not used, see `Watch::source` to create a watch without them
+    /// and `EventStream::handle_concurrent` to handle the events concurrently.
+    ///
+    /// # Errors
+    /// Returns an `Error` if the watchers fail to initialize or the startup events can't be computed
+    pub fn stream(&self) -> Result<EventStream, Error> {
+        let config = self.source_config();
+        let capacity = match config.queue_capacity {
+            0 => DEFAULT_QUEUE_CAPACITY,
+            capacity => capacity,
+        };
+        let mut session = Session::open(config)?;
+        let startup_events = session.startup()?;
+        let events_filter = self.events_filter().to_vec();
+        let handled = session.handled_state();
+
+        let (mut tx, rx) = mpsc::channel(capacity);
+        let (stop_tx, stop_rx) = bounded(0);
+
+        let thread = thread::spawn(move || {
+            let mut forward = |event_result: Result<notify::Event, notify::Error>| {
+                let (item, paths) = match event_result {
+                    Ok(event) if matches_filter(&event, &events_filter) => {
+                        let paths = event.paths.clone();
+                        (Ok(WatchEvent::new(event)), paths)
+                    }
+                    Ok(_) => return true,
+                    Err(e) => (Err(Error::other(e)), Vec::new()),
+                };
+                // Waits for room in the stream, returns false once the stream is dropped
+                if block_on(tx.send(item)).is_err() {
+                    return false;
+                }
+                // Sent to the stream, the event counts as handled in the state file
+                if let Some(handled) = &handled {
+                    handled.handled(&paths);
+                }
+                true
+            };
+
+            // The state file is saved even if the stream was dropped during the startup events
+            let dropped = startup_events.into_iter().any(|event| !forward(Ok(event)));
+            if !dropped {
+                session.run(&stop_rx, &mut forward);
+            }
+            session.close();
+        });
+
+        Ok(EventStream {
+            rx,
+            stop_tx: Some(stop_tx),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl EventStream {
+    /// Handle the events with an async handler, running at most `limit` handlers concurrently
+    ///
+    /// Watch errors are printed and skipped. Returns once the watchers stop.
+    pub async fn handle_concurrent<F, Fut>(self, limit: usize, handler: F)
+    where
+        F: FnMut(WatchEvent) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        self.filter_map(|event_result| async move {
+            match event_result {
+                Ok(event) => Some(event),
+                Err(e) => {
+                    error!(error = %e, "Watch error");
+                    None
+                }
+            }
+        })
+        .for_each_concurrent(limit, handler)
+        .await
+    }
+}
+
+impl Drop for EventStream {
+    /// Stop the watchers and wait for the state file to be saved
+    fn drop(&mut self) {
+        // Closing the stream wakes the session thread up if it waits for room
+        self.rx.close();
+        self.stop_tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Result<WatchEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stream_yields_filtered_events() {
+        let dir = TempDir::new("stream");
+        fs::write(dir.join("existing.txt"), "").unwrap();
+
+        let events = vec!["create".to_string()];
+        let mut stream = Watch::source(&dir, false, &events)
+            .initial_scan(true)
+            .stream()
+            .unwrap();
+
+        block_on(async {
+            let event = stream.next().await.unwrap().unwrap();
+            assert_eq!(event.kind, "create");
+            assert_eq!(
+                event.path(),
+                Some(dir.canonicalize().unwrap().join("existing.txt").as_path())
+            );
+
+            thread::sleep(Duration::from_millis(100));
+            fs::write(dir.join("new.txt"), "").unwrap();
+            let event = stream.next().await.unwrap().unwrap();
+            assert_eq!(event.kind, "create");
+            assert!(event.path().unwrap().ends_with("new.txt"));
+        });
+    }
+
+    #[test]
+    fn test_drop_during_startup_events_saves_the_state_file() {
+        let dir = TempDir::new("stream-drop");
+        let watched = dir.join("watched");
+        fs::create_dir(&watched).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(watched.join(name), "").unwrap();
+        }
+        let state_file = dir.join("state");
+
+        let events = vec!["create".to_string()];
+        // The stream is full before the end of the startup events, the session thread waits for room
+        let stream = Watch::source(&watched, false, &events)
+            .initial_scan(true)
+            .state_file(&state_file)
+            .queue_capacity(1)
+            .stream()
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(stream);
+
+        // The events sent to the stream before it was dropped are saved as handled
+        let state = fs::read_to_string(&state_file).unwrap();
+        assert!(state.contains(".txt"));
+    }
+}
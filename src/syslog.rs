@@ -0,0 +1,334 @@
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use crate::sink::record_fields;
+use crate::util::format_timestamp;
+
+/// Socket of the local syslog daemon
+pub const DEFAULT_SOCKET: &str = "/dev/log";
+
+/// ID of the structured data element carrying the fields of the records
+///
+/// 32473 is the private enterprise number reserved for documentation by RFC 5612.
+pub const SD_ID: &str = "watchcrab@32473";
+
+/// Severity of the messages, "informational"
+const SEVERITY: u8 = 6;
+
+/// Syslog facility of the messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Facility(u8);
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+impl Facility {
+    pub const USER: Facility = Facility(1);
+    pub const DAEMON: Facility = Facility(3);
+}
+
+impl Default for Facility {
+    fn default() -> Self {
+        Facility::USER
+    }
+}
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FACILITIES
+            .iter()
+            .position(|name| *name == s)
+            .map(|code| Facility(code as u8))
+            .ok_or_else(|| {
+                format!(
+                    "Invalid syslog facility '{}', expected one of: {}",
+                    s,
+                    FACILITIES.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for Facility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", FACILITIES[self.0 as usize])
+    }
+}
+
+/// Options of a syslog sink
+///
+/// * `facility` - Facility of the messages, `user` by default
+/// * `app_name` - APP-NAME of the messages, used by the syslog daemon to tell the programs apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogOptions {
+    pub facility: Facility,
+    pub app_name: String,
+}
+
+impl Default for SyslogOptions {
+    fn default() -> Self {
+        SyslogOptions {
+            facility: Facility::default(),
+            app_name: String::from("watchcrab"),
+        }
+    }
+}
+
+/// Where a syslog sink sends its messages: `unix:<path>` for a datagram socket, `udp:<host>:<port>` for a remote daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogAddr {
+    Unix(PathBuf),
+    Udp(String),
+}
+
+impl Default for SyslogAddr {
+    fn default() -> Self {
+        SyslogAddr::Unix(PathBuf::from(DEFAULT_SOCKET))
+    }
+}
+
+impl FromStr for SyslogAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(SyslogAddr::Unix(PathBuf::from(path))),
+            Some(("udp", address)) if address.contains(':') => {
+                Ok(SyslogAddr::Udp(address.to_string()))
+            }
+            _ => Err(format!(
+                "Invalid syslog address '{}', expected unix:<path> or udp:<host>:<port>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SyslogAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyslogAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            SyslogAddr::Udp(address) => write!(f, "udp:{}", address),
+        }
+    }
+}
+
+enum Socket {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+/// Sink sending each record as an RFC 5424 message
+///
+/// The fields of the JSON records are sent as the parameters of the `watchcrab@32473` structured data element,
+/// and the record itself as the message, so the syslog daemon can index the fields without parsing the message.
+///
+/// ```text
+/// <14>1 2024-05-01T12:00:00.000000Z host watchcrab 4242 - [watchcrab@32473 Kind="Create(File)" Path="/tmp/new.txt"] {"Kind": ...}
+/// ```
+pub struct Syslog {
+    socket: Socket,
+    options: SyslogOptions,
+    hostname: String,
+}
+
+impl Syslog {
+    /// Open a socket to the syslog daemon
+    ///
+    /// # Errors
+    /// Returns an `Error` if the socket can't be opened, or is a Unix domain socket on a platform without them
+    pub fn connect(addr: &SyslogAddr, options: SyslogOptions) -> Result<Syslog, Error> {
+        let socket = match addr {
+            #[cfg(unix)]
+            SyslogAddr::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Socket::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            SyslogAddr::Unix(_) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Unix domain sockets are not supported on this platform",
+                ));
+            }
+            SyslogAddr::Udp(address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(address)?;
+                Socket::Udp(socket)
+            }
+        };
+        Ok(Syslog {
+            socket,
+            options,
+            hostname: hostname(),
+        })
+    }
+
+    /// Send a record as a message
+    ///
+    /// # Errors
+    /// Returns an `Error` if the message can't be sent
+    pub fn send(&self, record: &str) -> Result<(), Error> {
+        let message = format_message(&self.options, &self.hostname, SystemTime::now(), record);
+        let sent = match &self.socket {
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(message.as_bytes()),
+            Socket::Udp(socket) => socket.send(message.as_bytes()),
+        }?;
+        if sent < message.len() {
+            return Err(Error::new(ErrorKind::WriteZero, "Message truncated"));
+        }
+        Ok(())
+    }
+}
+
+/// Format a record as an RFC 5424 message
+fn format_message(
+    options: &SyslogOptions,
+    hostname: &str,
+    time: SystemTime,
+    record: &str,
+) -> String {
+    let fields = record_fields(record);
+    let structured_data = if fields.is_empty() {
+        String::from("-")
+    } else {
+        let params: String = fields
+            .iter()
+            .map(|(name, value)| format!(" {}=\"{}\"", param_name(name), escape_param(value)))
+            .collect();
+        format!("[{}{}]", SD_ID, params)
+    };
+    format!(
+        "<{}>1 {} {} {} {} - {} {}",
+        options.facility.0 * 8 + SEVERITY,
+        format_timestamp(time),
+        header_field(hostname, 255),
+        header_field(&options.app_name, 48),
+        std::process::id(),
+        structured_data,
+        record
+    )
+}
+
+/// Header fields are printable ASCII without spaces, `-` when empty
+fn header_field(value: &str, max_len: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if value.is_empty() {
+        String::from("-")
+    } else {
+        value
+    }
+}
+
+/// Parameter names are at most 32 printable ASCII characters, without `=`, space, `]` and `"`
+fn param_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '=' | ']' | '"' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .take(32)
+        .collect();
+    if name.is_empty() {
+        String::from("_")
+    } else {
+        name
+    }
+}
+
+/// `"`, `\` and `]` are escaped with a backslash in parameter values
+fn escape_param(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .ok()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::json_record;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_format_message_with_structured_data() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_714_564_800_123_456);
+        let options = SyslogOptions {
+            facility: "local0".parse().unwrap(),
+            ..SyslogOptions::default()
+        };
+        let record = r#"{"Kind": "Create(File)", "Path": "/tmp/a \"b\"]"}"#;
+        let message = format_message(&options, "host", time, record);
+        let pid = std::process::id();
+        assert_eq!(
+            message,
+            format!(
+                r#"<134>1 2024-05-01T12:00:00.123456Z host watchcrab {} - [watchcrab@32473 Kind="Create(File)" Path="/tmp/a \"b\"\]"] {}"#,
+                pid, record
+            )
+        );
+        assert!(format_message(&options, "", time, "plain text").contains(" - - plain text"));
+    }
+
+    #[test]
+    fn test_structured_data_of_records_with_special_characters() {
+        let time = UNIX_EPOCH;
+        let event = json_record(&[("Kind", "Create(File)"), ("Path", r"C:\Users\a]b.txt")]);
+        let message = format_message(&SyslogOptions::default(), "host", time, &event);
+        assert!(
+            message.contains(r#"[watchcrab@32473 Kind="Create(File)" Path="C:\\Users\\a\]b.txt"]"#)
+        );
+
+        let output = json_record(&[("stdout", "say \"hi\"\nbye"), ("stderr", "")]);
+        let message = format_message(&SyslogOptions::default(), "host", time, &output);
+        assert!(message.contains("[watchcrab@32473 stderr=\"\" stdout=\"say \\\"hi\\\"\nbye\"]"));
+    }
+
+    #[test]
+    fn test_send_over_udp() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = SyslogAddr::Udp(listener.local_addr().unwrap().to_string());
+        let syslog = Syslog::connect(&addr, SyslogOptions::default()).unwrap();
+        syslog.send(r#"{"stdout": "ok"}"#).unwrap();
+
+        let mut buf = [0; 1024];
+        let len = listener.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+        assert!(message.starts_with("<14>1 "));
+        assert!(message.ends_with(r#"[watchcrab@32473 stdout="ok"] {"stdout": "ok"}"#));
+    }
+}
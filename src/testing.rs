@@ -0,0 +1,648 @@
+//! Test doubles for the code built on `Watch`, enabled with the `testing` feature
+//!
+//! * `FakeWatcher` and `FakeBackend` - A `notify::Watcher` receiving the events injected by the test instead of the filesystem
+//! * `Harness` - Runs events through the filter, layers and handler of a watch synchronously, without watcher nor signals
+//! * `VirtualClock` - A clock advanced by the test, for `Debounce`, `Throttle` and `RateLimit`
+//! * `HandlerSpy` and `CommandSpy` - Handlers recording their calls, and the commands they would have run
+//!
+//! # Examples
+//!
+//! ```
+//! use std::path::Path;
+//! use std::time::Duration;
+//! use notify::event::{EventKind, ModifyKind};
+//! use notify::Event;
+//! use watchcrab::layer::Debounce;
+//! use watchcrab::testing::{CommandSpy, Harness, VirtualClock};
+//! use watchcrab::watch::Watch;
+//!
+//! let clock = VirtualClock::new();
+//! let spy = CommandSpy::new(&["cargo", "fmt", "--", "{path}"]);
+//! let events = vec!["modify".to_string()];
+//! let watch = Watch::new_fallible(Path::new("./"), true, &events, spy.handler(), 1)
+//!     .layer(Debounce::new(Duration::from_millis(200)).clock(clock.clone()));
+//!
+//! let harness = Harness::new(watch);
+//! for _ in 0..3 {
+//!     harness.inject(Event::new(EventKind::Modify(ModifyKind::Any)).add_path("/src/main.rs".into()));
+//! }
+//! clock.advance(Duration::from_millis(200));
+//! assert!(spy.wait_for(1, Duration::from_secs(5)));
+//! harness.finish().unwrap();
+//! assert_eq!(spy.invocations(), vec!["cargo fmt -- /src/main.rs"]);
+//! ```
+
+use std::fmt;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use notify::{Config, Event, EventHandler, RecursiveMode, Watcher, WatcherKind};
+
+use crate::event::EventRecord;
+use crate::handler::{Dispatcher, HandlerError, HandlerStats};
+use crate::layer::Clock;
+use crate::util::parse_command;
+use crate::watch::Watch;
+
+/// Handler and watched paths of a `FakeWatcher`, shared with the `FakeBackend` it is attached to
+struct FakeWatches {
+    handler: Mutex<Option<Box<dyn EventHandler>>>,
+    watches: Mutex<Vec<(PathBuf, RecursiveMode)>>,
+}
+
+impl FakeWatches {
+    /// Whether an event of `path` would be reported by a real backend
+    fn covers(&self, path: &Path) -> bool {
+        self.watches
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(root, mode)| match mode {
+                RecursiveMode::Recursive => path.starts_with(root),
+                RecursiveMode::NonRecursive => path == root || path.parent() == Some(root),
+            })
+    }
+
+    /// Send an event to the handler if one of its paths is watched, or if it has no path
+    fn emit(&self, event: Result<Event, notify::Error>) -> bool {
+        let watched = match &event {
+            Ok(event) if !event.paths.is_empty() => {
+                event.paths.iter().any(|path| self.covers(path))
+            }
+            _ => !self.watches.lock().unwrap().is_empty(),
+        };
+        if !watched {
+            return false;
+        }
+        let mut handler = self.handler.lock().unwrap();
+        match handler.as_mut() {
+            Some(handler) => {
+                handler.handle_event(event);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Watcher with the interface of `notify::RecommendedWatcher` that never touches the filesystem, the watched paths don't have to exist
+///
+/// The events are emitted by the test with `FakeWatcher::emit`, or through the `FakeBackend` it is attached to,
+/// and only reach the handler if one of their paths is watched, like with a real backend.
+pub struct FakeWatcher {
+    watches: Arc<FakeWatches>,
+}
+
+impl FakeWatcher {
+    /// Send an event to the handler, returns whether it was delivered
+    pub fn emit(&self, event: Result<Event, notify::Error>) -> bool {
+        self.watches.emit(event)
+    }
+
+    /// Paths currently watched
+    pub fn watched(&self) -> Vec<PathBuf> {
+        self.watches
+            .watches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+}
+
+impl Watcher for FakeWatcher {
+    fn new<F: EventHandler>(event_handler: F, _config: Config) -> notify::Result<Self> {
+        Ok(FakeWatcher {
+            watches: Arc::new(FakeWatches {
+                handler: Mutex::new(Some(Box::new(event_handler))),
+                watches: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        self.watches
+            .watches
+            .lock()
+            .unwrap()
+            .push((path.to_path_buf(), recursive_mode));
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        let mut watches = self.watches.watches.lock().unwrap();
+        let count = watches.len();
+        watches.retain(|(root, _)| root != path);
+        if watches.len() == count {
+            return Err(notify::Error::watch_not_found().add_path(path.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    fn kind() -> WatcherKind {
+        WatcherKind::NullWatcher
+    }
+}
+
+/// Backend of the roots of a watch started with `Watch::fake_backend`, receiving the events injected by the test
+///
+/// The watch registers a `FakeWatcher` per root when it starts, the events are then injected with `FakeBackend::inject`
+/// and go through the event queue, the rescans, the filter, the layers and the handler like the events of a real backend.
+/// `FakeBackend::close` disconnects the watchers, so `Watch::start` handles the events already injected and returns.
+///
+/// The roots are watched as given: they are not canonicalized and don't have to exist, and the watch doesn't install
+/// the signal handlers of the process. The options reading the roots (`Watch::initial_scan`, `Watch::state_file` and
+/// `Watch::rescan_on_overflow`) still read the filesystem.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::Path;
+/// use std::time::Duration;
+/// use notify::event::{CreateKind, EventKind};
+/// use notify::Event;
+/// use watchcrab::testing::{FakeBackend, HandlerSpy};
+/// use watchcrab::watch::Watch;
+///
+/// let root = Path::new("/project");
+/// let fake = FakeBackend::new();
+/// let spy = HandlerSpy::new();
+/// let events = vec!["create".to_string()];
+///
+/// std::thread::scope(|scope| {
+///     // A watch is not `Send`, it is created by the thread running it
+///     let running = scope.spawn(|| {
+///         Watch::new_fallible(root, true, &events, spy.handler(), 1)
+///             .fake_backend(&fake)
+///             .start()
+///     });
+///     assert!(fake.wait_for_watchers(1, Duration::from_secs(5)));
+///     fake.inject(Event::new(EventKind::Create(CreateKind::File)).add_path(root.join("new.txt")));
+///     assert!(spy.wait_for(1, Duration::from_secs(5)));
+///     fake.close();
+///     running.join().unwrap().unwrap();
+/// });
+/// assert_eq!(spy.paths(), vec![root.join("new.txt")]);
+/// ```
+#[derive(Clone, Default)]
+pub struct FakeBackend {
+    watchers: Arc<(Mutex<Vec<Arc<FakeWatches>>>, Condvar)>,
+}
+
+impl FakeBackend {
+    pub fn new() -> FakeBackend {
+        FakeBackend::default()
+    }
+
+    /// Receive the events injected in the backend with `watcher`
+    pub fn attach(&self, watcher: &FakeWatcher) {
+        let (watchers, attached) = &*self.watchers;
+        watchers.lock().unwrap().push(Arc::clone(&watcher.watches));
+        attached.notify_all();
+    }
+
+    /// Send an event to the watchers watching one of its paths, returns whether a watcher received it
+    pub fn inject(&self, event: Event) -> bool {
+        let watchers = self.watchers.0.lock().unwrap().clone();
+        let mut delivered = false;
+        for watches in watchers {
+            delivered |= watches.emit(Ok(event.clone()));
+        }
+        delivered
+    }
+
+    /// Send an error to the first watcher still watching, like a backend failing to read its events
+    pub fn inject_error(&self, error: notify::Error) -> bool {
+        let watchers = self.watchers.0.lock().unwrap().clone();
+        let watching = watchers
+            .iter()
+            .find(|watches| !watches.watches.lock().unwrap().is_empty());
+        watching.is_some_and(|watches| watches.emit(Err(error)))
+    }
+
+    /// Paths watched by the attached watchers
+    pub fn watched(&self) -> Vec<PathBuf> {
+        let watchers = self.watchers.0.lock().unwrap();
+        watchers
+            .iter()
+            .flat_map(|watches| {
+                watches
+                    .watches
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(path, _)| path.clone())
+                    .collect::<Vec<PathBuf>>()
+            })
+            .collect()
+    }
+
+    /// Wait until `count` watchers are attached, typically once the watch started, returns false on timeout
+    pub fn wait_for_watchers(&self, count: usize, timeout: Duration) -> bool {
+        let (watchers, attached) = &*self.watchers;
+        let watchers = watchers.lock().unwrap();
+        let (watchers, _) = attached
+            .wait_timeout_while(watchers, timeout, |watchers| watchers.len() < count)
+            .unwrap();
+        watchers.len() >= count
+    }
+
+    /// Drop the handlers of the watchers, as if every watcher stopped
+    ///
+    /// The event queue of the watch is disconnected once the events already injected are received, and `Watch::start` returns.
+    pub fn close(&self) {
+        for watches in self.watchers.0.lock().unwrap().iter() {
+            watches.handler.lock().unwrap().take();
+        }
+    }
+}
+
+impl fmt::Debug for FakeBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FakeBackend")
+            .field("watched", &self.watched())
+            .finish()
+    }
+}
+
+/// Runs events through the events filter, layers, thread pool and handler of a watch, without watcher nor signal handling
+///
+/// `Harness::inject` returns once the event was passed to the handler, or to the thread pool or a layer holding it
+/// (e.g. `Debounce`), so with one thread and no such layer the handler was called when it returns.
+/// The roots and the other options of the event source are ignored, see `FakeBackend` to test them.
+pub struct Harness<'a> {
+    watch: Watch<'a>,
+    dispatcher: Arc<Dispatcher>,
+    // Keeps the failure channel connected, the failure limit is checked with `Harness::stopped`
+    _failure_rx: Receiver<()>,
+}
+
+impl<'a> Harness<'a> {
+    pub fn new(watch: Watch<'a>) -> Harness<'a> {
+        let (dispatcher, failure_rx) = watch.dispatcher(None);
+        Harness {
+            watch,
+            dispatcher,
+            _failure_rx: failure_rx,
+        }
+    }
+
+    /// Handle an event as if the backend reported it, ignored once the handler failed too many consecutive times
+    pub fn inject(&self, event: Event) {
+        if !self.dispatcher.stopped() {
+            self.watch.process_event(&self.dispatcher, Ok(event));
+        }
+    }
+
+    /// Whether the handler failed too many consecutive times, see `Watch::max_consecutive_failures`
+    pub fn stopped(&self) -> bool {
+        self.dispatcher.stopped()
+    }
+
+    /// Counters of the events handled successfully, failed and panicked
+    pub fn handler_stats(&self) -> Arc<HandlerStats> {
+        self.watch.handler_stats()
+    }
+
+    /// Wait for the events still held by the thread pool and the layers to be handled
+    ///
+    /// # Errors
+    /// Returns an `Error` if the handler failed too many consecutive times, like `Watch::start`
+    pub fn finish(self) -> Result<(), Error> {
+        self.watch.finish(self.dispatcher)
+    }
+}
+
+/// Time of a `VirtualClock` and the receivers waiting for it
+struct VirtualTime {
+    now: Instant,
+    timers: Vec<(Instant, Sender<Instant>)>,
+}
+
+/// Clock that only moves when the test advances it
+///
+/// The clones of a clock share its time, pass a clone to the layers and advance the original.
+/// A layer sleeping on the clock (`Throttle` or `RateLimit` with `ThrottlePolicy::Queue`) blocks the thread calling it
+/// until the clock is advanced, so advance it from another thread or use a thread pool.
+#[derive(Clone)]
+pub struct VirtualClock {
+    time: Arc<Mutex<VirtualTime>>,
+    start: Instant,
+}
+
+impl VirtualClock {
+    /// Clock starting at the current real time
+    pub fn new() -> VirtualClock {
+        let start = Instant::now();
+        VirtualClock {
+            time: Arc::new(Mutex::new(VirtualTime {
+                now: start,
+                timers: Vec::new(),
+            })),
+            start,
+        }
+    }
+
+    /// Move the clock forward, waking the layers waiting for a time that has now passed
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.time.lock().unwrap();
+        time.now += duration;
+        let now = time.now;
+        time.timers.retain(|(deadline, tx)| {
+            if *deadline <= now {
+                let _ = tx.try_send(now);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Time the clock was advanced by since it was created
+    pub fn elapsed(&self) -> Duration {
+        self.time.lock().unwrap().now - self.start
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> VirtualClock {
+        VirtualClock::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.time.lock().unwrap().now
+    }
+
+    fn at(&self, deadline: Instant) -> Receiver<Instant> {
+        let (tx, rx) = bounded(1);
+        let mut time = self.time.lock().unwrap();
+        if deadline <= time.now {
+            let _ = tx.try_send(time.now);
+        } else {
+            time.timers.push((deadline, tx));
+        }
+        rx
+    }
+}
+
+/// Calls recorded by a spy, and the condition notified on each call
+struct Calls<T> {
+    calls: Mutex<Vec<T>>,
+    called: Condvar,
+}
+
+impl<T: Clone> Calls<T> {
+    fn new() -> Calls<T> {
+        Calls {
+            calls: Mutex::new(Vec::new()),
+            called: Condvar::new(),
+        }
+    }
+
+    fn push(&self, call: T) {
+        self.calls.lock().unwrap().push(call);
+        self.called.notify_all();
+    }
+
+    fn get(&self) -> Vec<T> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn wait_for(&self, count: usize, timeout: Duration) -> bool {
+        let calls = self.calls.lock().unwrap();
+        let (calls, _) = self
+            .called
+            .wait_timeout_while(calls, timeout, |calls| calls.len() < count)
+            .unwrap();
+        calls.len() >= count
+    }
+}
+
+/// Handler recording the events it receives
+///
+/// The clones of a spy share its calls, pass `HandlerSpy::handler` to the watch and make the assertions on the spy.
+#[derive(Clone)]
+pub struct HandlerSpy {
+    calls: Arc<Calls<Event>>,
+}
+
+impl HandlerSpy {
+    pub fn new() -> HandlerSpy {
+        HandlerSpy {
+            calls: Arc::new(Calls::new()),
+        }
+    }
+
+    /// Handler recording each event and returning `Ok(())`
+    pub fn handler(&self) -> impl Fn(Event) -> Result<(), HandlerError> + Send + Sync + 'static {
+        let calls = Arc::clone(&self.calls);
+        move |event| {
+            calls.push(event);
+            Ok(())
+        }
+    }
+
+    /// Events received, in the order the handler was called
+    pub fn events(&self) -> Vec<Event> {
+        self.calls.get()
+    }
+
+    /// First path of each event received
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.calls
+            .get()
+            .into_iter()
+            .filter_map(|event| event.paths.into_iter().next())
+            .collect()
+    }
+
+    /// Wait until the handler was called `count` times, returns false on timeout
+    pub fn wait_for(&self, count: usize, timeout: Duration) -> bool {
+        self.calls.wait_for(count, timeout)
+    }
+}
+
+impl Default for HandlerSpy {
+    fn default() -> HandlerSpy {
+        HandlerSpy::new()
+    }
+}
+
+/// Predicate of the commands a `CommandSpy` makes fail
+type CommandPredicate = dyn Fn(&str) -> bool + Send + Sync;
+
+/// Handler recording the commands it would run for the events, instead of running them
+///
+/// The `{path}` and `{kind}` placeholders of the arguments are replaced like with the `--args` option of the command line tool,
+/// and the arguments joined with spaces, as passed to the shell.
+#[derive(Clone)]
+pub struct CommandSpy {
+    args: Arc<Vec<String>>,
+    invocations: Arc<Calls<String>>,
+    fail_if: Option<Arc<CommandPredicate>>,
+}
+
+impl CommandSpy {
+    pub fn new(args: &[&str]) -> CommandSpy {
+        CommandSpy {
+            args: Arc::new(args.iter().map(|arg| arg.to_string()).collect()),
+            invocations: Arc::new(Calls::new()),
+            fail_if: None,
+        }
+    }
+
+    /// Make the commands matching a predicate fail, as if they exited with status 1
+    pub fn fail_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.fail_if = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Handler recording the command of each event
+    ///
+    /// # Errors
+    /// The handler returns an error for the commands matching the predicate of `CommandSpy::fail_if`
+    pub fn handler(&self) -> impl Fn(Event) -> Result<(), HandlerError> + Send + Sync + 'static {
+        let spy = self.clone();
+        move |event| {
+            let record = EventRecord::new(&event);
+            let path = record
+                .paths
+                .first()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let command = parse_command(&spy.args, &path, &record.detail).join(" ");
+            let failed = spy
+                .fail_if
+                .as_ref()
+                .is_some_and(|fail_if| fail_if(&command));
+            spy.invocations.push(command);
+            if failed {
+                return Err("Command exited with exit status: 1".into());
+            }
+            Ok(())
+        }
+    }
+
+    /// Commands run, in order
+    pub fn invocations(&self) -> Vec<String> {
+        self.invocations.get()
+    }
+
+    /// Wait until `count` commands were run, returns false on timeout
+    pub fn wait_for(&self, count: usize, timeout: Duration) -> bool {
+        self.invocations.wait_for(count, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::SkipReason;
+    use crate::layer::{Debounce, Throttle};
+    use notify::event::{CreateKind, ModifyKind};
+    use notify::EventKind;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn modify(path: &str) -> Event {
+        Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path.into())
+    }
+
+    #[test]
+    fn test_harness_with_virtual_clock() {
+        let clock = VirtualClock::new();
+        let spy = CommandSpy::new(&["make", "{path}"]).fail_if(|command| command.ends_with(".bad"));
+        let events = vec!["modify".to_string()];
+        let (skipped_tx, skipped_rx) = crossbeam_channel::unbounded();
+        let debounce = Debounce::new(Duration::from_millis(200))
+            .clock(clock.clone())
+            .on_skipped(move |event, reason| {
+                let _ = skipped_tx.send((event.paths[0].clone(), reason));
+            });
+        let watch = Watch::new_fallible(Path::new("/project"), true, &events, spy.handler(), 1)
+            .layer(debounce)
+            .on_error(|_, _| {});
+        let harness = Harness::new(watch);
+
+        harness.inject(modify("/project/a.c"));
+        harness.inject(
+            Event::new(EventKind::Create(CreateKind::File)).add_path("/project/b.c".into()),
+        );
+        clock.advance(Duration::from_millis(150));
+        harness.inject(modify("/project/a.c"));
+        harness.inject(modify("/project/c.bad"));
+        clock.advance(Duration::from_millis(100));
+        // Only the first event of a.c was due, and the debounce thread replaced it by the second one
+        assert_eq!(
+            skipped_rx.recv_timeout(TIMEOUT),
+            Ok((PathBuf::from("/project/a.c"), SkipReason::Debounce))
+        );
+
+        clock.advance(Duration::from_millis(100));
+        assert!(spy.wait_for(2, TIMEOUT));
+        assert_eq!(clock.elapsed(), Duration::from_millis(350));
+        harness.finish().unwrap();
+        let mut invocations = spy.invocations();
+        invocations.sort();
+        assert_eq!(
+            invocations,
+            vec!["make /project/a.c", "make /project/c.bad"]
+        );
+
+        let clock = VirtualClock::new();
+        let spy = HandlerSpy::new();
+        let events = vec!["all".to_string()];
+        let watch = Watch::new_fallible(Path::new("/project"), true, &events, spy.handler(), 1)
+            .layer(Throttle::new(Duration::from_secs(1)).clock(clock.clone()));
+        let harness = Harness::new(watch);
+        harness.inject(modify("/project/a.c"));
+        harness.inject(modify("/project/a.c"));
+        clock.advance(Duration::from_secs(1));
+        harness.inject(modify("/project/a.c"));
+        assert_eq!(spy.events().len(), 2);
+        // The event dropped by the throttle never reached the handler
+        assert_eq!(harness.handler_stats().succeeded(), 2);
+    }
+
+    // The backend of a watch can only be replaced with the `testing` feature
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_fake_backend_feeds_a_started_watch() {
+        let dir = crate::test_util::TempDir::new("testing");
+        let root = dir.canonicalize().unwrap();
+
+        let fake = FakeBackend::new();
+        let spy = HandlerSpy::new();
+        let events = vec!["create".to_string()];
+
+        std::thread::scope(|scope| {
+            let running = scope.spawn(|| {
+                Watch::new_fallible(&root, false, &events, spy.handler(), 1)
+                    .fake_backend(&fake)
+                    .start()
+            });
+            assert!(fake.wait_for_watchers(1, TIMEOUT));
+            assert_eq!(fake.watched(), vec![root.clone()]);
+
+            let create =
+                |path: PathBuf| Event::new(EventKind::Create(CreateKind::File)).add_path(path);
+            assert!(fake.inject(create(root.join("a.txt"))));
+            // Not reported by a non recursive watch, like with a real backend
+            assert!(!fake.inject(create(root.join("sub/b.txt"))));
+            assert!(fake.inject(modify(root.join("a.txt").to_str().unwrap())));
+            assert!(fake.inject(create(root.join("c.txt"))));
+            fake.close();
+            running.join().unwrap().unwrap();
+        });
+        assert_eq!(spy.paths(), vec![root.join("a.txt"), root.join("c.txt")]);
+    }
+}
@@ -5,8 +5,11 @@ use std::os::unix::process::CommandExt;
 
 use std::process::{Child, Command, Stdio};
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs::OpenOptions, path::PathBuf};
 
+use tracing::error;
+
 #[cfg(target_family = "windows")]
 use std::os::windows::process::CommandExt;
 
@@ -14,18 +17,16 @@ use std::os::windows::process::CommandExt;
 #[cfg(target_family = "windows")]
 use windows::Win32::System::Threading::CREATE_NO_WINDOW;
 
-lazy_static::lazy_static! {
-    static ref LOG_FILE_MUTEX: Mutex<()> = Mutex::new(());
-}
+static LOG_FILE_MUTEX: Mutex<()> = Mutex::new(());
 
 ///Replace the '{path}' and '{kind}' placeholders in a command with the given path and kind
 ///
 /// # Arguments
 /// * `command` - Command to replace the placeholder in
 /// * `path` - Path to replace the placeholder with
-pub fn parse_command(command: &Vec<String>, path: &str, kind: &str) -> Vec<String> {
+pub fn parse_command(command: &[String], path: &str, kind: &str) -> Vec<String> {
     let mut parsed_command = Vec::new();
-    for arg in command.clone() {
+    for arg in command.iter().cloned() {
         if arg.contains("{path}") || arg.contains("{kind}") {
             let tmp_arg = arg.replace("{path}", path);
             let tmp_arg = tmp_arg.replace("{kind}", kind);
@@ -39,6 +40,8 @@ pub fn parse_command(command: &Vec<String>, path: &str, kind: &str) -> Vec<Strin
 
 ///Write the output to a log file thread-safely
 ///
+/// Same as `write_to_log_file`, holding a lock so only one thread writes to the log file at a time.
+///
 /// # Arguments
 /// * `output_file_path` - Path to the log file
 /// * `output` - Output to write to the log file
@@ -48,16 +51,13 @@ pub fn parse_command(command: &Vec<String>, path: &str, kind: &str) -> Vec<Strin
 ///
 /// # Errors
 /// Errors if the output can't be written to the log file
+#[deprecated(
+    note = "use sink::LogWriter, which keeps the file open and writes from a dedicated thread"
+)]
 pub fn write_to_log_file_async(output_file_path: &PathBuf, output: &str) {
-    let _lock = LOG_FILE_MUTEX.lock().unwrap(); // To make sure only one thread writes to the log file at a time
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(output_file_path)
-        .expect("Unable to open log file");
-    if let Err(e) = writeln!(file, "{}", output) {
-        eprintln!("Couldn't write to log file: {}", e);
-    }
+    let _lock = LOG_FILE_MUTEX.lock().unwrap_or_else(|e| e.into_inner()); // To make sure only one thread writes to the log file at a time
+    #[allow(deprecated)]
+    write_to_log_file(output_file_path, output)
 }
 
 ///Write the output to a log file
@@ -71,20 +71,131 @@ pub fn write_to_log_file_async(output_file_path: &PathBuf, output: &str) {
 ///
 /// # Errors
 /// Errors if the output can't be written to the log file
+#[deprecated(
+    note = "use sink::LogWriter, which keeps the file open and writes from a dedicated thread"
+)]
 pub fn write_to_log_file(output_file_path: &PathBuf, output: &str) {
     let mut file = OpenOptions::new()
-        .write(true)
         .append(true)
         .open(output_file_path)
         .expect("Unable to open log file");
-    if let Err(e) = writeln!(file, "{}", output) {
-        eprintln!("Couldn't write to log file: {}", e);
+    if let Err(e) = file.write_all(format!("{}\n", output).as_bytes()) {
+        error!(error = %e, "Couldn't write to log file");
     }
 }
 
+/// Format a time as an RFC 3339 timestamp in UTC with microseconds, like `2024-05-01T12:00:00.000000Z`
+pub fn format_timestamp(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = elapsed.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let seconds_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+        elapsed.subsec_micros()
+    )
+}
+
+/// Date of a number of days since 1970-01-01, from Howard Hinnant's `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Number of days since 1970-01-01 of a date, from Howard Hinnant's `days_from_civil`
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Parse a duration with a `s`, `m`, `h` or `d` suffix, like `90s`, `30m` or `7d`
+///
+/// # Errors
+/// Returns an error message if the duration is not a number followed by a known suffix
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "Invalid duration '{}', expected a number followed by s, m, h or d (e.g. 30m)",
+            duration
+        )
+    };
+    let duration = duration.trim();
+    let (number, unit) = duration.split_at(duration.len().saturating_sub(1));
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+/// Parse a point in time, either a duration before `now` (see `parse_duration`) or a UTC date like `2024-05-01`,
+/// `2024-05-01T22:00:00Z` or `2024-05-01 22:00`
+///
+/// # Errors
+/// Returns an error message if the time is neither a duration nor a date
+pub fn parse_time(time: &str, now: SystemTime) -> Result<SystemTime, String> {
+    if let Ok(ago) = parse_duration(time) {
+        return Ok(now.checked_sub(ago).unwrap_or(UNIX_EPOCH));
+    }
+    let invalid = || {
+        format!(
+            "Invalid time '{}', expected a duration (e.g. 12h) or a UTC date (e.g. 2024-05-01T22:00:00Z)",
+            time
+        )
+    };
+    let time = time.trim().trim_end_matches('Z');
+    let (date, clock) = time.split_once(['T', ' ']).unwrap_or((time, "00:00:00"));
+    let numbers = |part: &str, separator: char| -> Option<Vec<u64>> {
+        part.split(separator).map(|n| n.parse().ok()).collect()
+    };
+    let date = numbers(date, '-')
+        .filter(|date| date.len() == 3)
+        .ok_or_else(invalid)?;
+    let clock = numbers(clock, ':')
+        .filter(|clock| clock.len() == 2 || clock.len() == 3)
+        .ok_or_else(invalid)?;
+    let (month, day) = (date[1] as u32, date[2] as u32);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || clock[0] > 23 || clock[1] > 59 {
+        return Err(invalid());
+    }
+    let days = days_from_civil(date[0] as i64, month, day);
+    let secs = days * 86400
+        + (clock[0] * 3600 + clock[1] * 60 + clock.get(2).copied().unwrap_or(0)) as i64;
+    u64::try_from(secs)
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .map_err(|_| invalid())
+}
+
 ///Execute a command on Unix disabling the termination signal for the child process
 #[cfg(target_family = "unix")]
-pub fn command_exec_unix(sh_cmd_split: &Vec<String>, args_str: String) -> Child {
+pub fn command_exec_unix(sh_cmd_split: &[String], args_str: String) -> Child {
     unsafe {
         Command::new(&sh_cmd_split[0])
             .arg(&sh_cmd_split[1])
@@ -105,7 +216,7 @@ pub fn command_exec_unix(sh_cmd_split: &Vec<String>, args_str: String) -> Child
 
 ///Execute a command on Windows disabling the termination signal for the child process
 #[cfg(target_family = "windows")]
-pub fn command_exec_windows(sh_cmd_split: &Vec<String>, args_str: String) -> Child {
+pub fn command_exec_windows(sh_cmd_split: &[String], args_str: String) -> Child {
     Command::new(&sh_cmd_split[0])
         .arg(&sh_cmd_split[1])
         .arg(args_str)
@@ -152,4 +263,19 @@ mod tests {
         let expected = vec!["echo".to_string(), "/path/to/other/thing/tmp".to_string()];
         assert_eq!(parse_command(&command, path, kind), expected);
     }
+
+    #[test]
+    fn test_parse_and_format_time() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_714_564_800);
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+        assert_eq!(parse_time("2h", now), Ok(now - Duration::from_secs(7200)));
+        assert_eq!(parse_time("2024-05-01T12:00:00Z", now), Ok(now));
+        assert_eq!(parse_time("2024-05-01 12:00", now), Ok(now));
+        assert_eq!(
+            parse_time("2024-05-01", now),
+            Ok(now - Duration::from_secs(12 * 3600))
+        );
+        assert!(parse_time("yesterday", now).is_err());
+        assert_eq!(format_timestamp(now), "2024-05-01T12:00:00.000000Z");
+    }
 }
@@ -1,10 +1,27 @@
 use std::io::Error;
 
-use std::{path::Path, sync::Arc};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, never, select, Receiver, Sender};
+use notify::Event;
+use tracing::{debug, error, info, info_span, Span};
 
-use crossbeam_channel::select;
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
-use threadpool::ThreadPool;
+use crate::backend::{Backend, PollOptions};
+use crate::event::{kind_name, matches_filter};
+use crate::executor::{KeyedExecutor, SerializeBy};
+use crate::handler::{
+    print_failure, Decision, Dispatcher, ErrorCallback, Handler, HandlerError, HandlerFailure,
+    HandlerStats, Reporter, SkipReason, Target,
+};
+use crate::layer::Layer;
+use crate::queue::OverflowPolicy;
+use crate::router::Router;
+use crate::session::{Session, SourceConfig};
+use crate::state::HandledState;
+#[cfg(feature = "testing")]
+use crate::testing::FakeBackend;
 
 #[cfg(target_family = "unix")]
 use signal_hook::{
@@ -18,12 +35,13 @@ use std::thread;
 #[cfg(target_family = "windows")]
 use std::sync::atomic::{AtomicBool, Ordering};
 #[cfg(target_family = "windows")]
-use std::time::Duration;
-#[cfg(target_family = "windows")]
 use windows::Win32::Foundation::BOOL;
 #[cfg(target_family = "windows")]
 use windows::Win32::System::Console::{SetConsoleCtrlHandler, CTRL_CLOSE_EVENT, CTRL_C_EVENT};
 
+/// Default interval between two saves of the state file
+pub const DEFAULT_STATE_INTERVAL: Duration = Duration::from_secs(60);
+
 // Global flag for Windows signal handling
 #[cfg(target_family = "windows")]
 static SHOULD_STOP: AtomicBool = AtomicBool::new(false);
@@ -34,7 +52,7 @@ static SHOULD_STOP: AtomicBool = AtomicBool::new(false);
 /// * `path` - Path to the directory to watch
 /// * `recursive` - Watch directories recursively
 /// * `events` - Events to watch for
-///     e.g. ["all"] or ["access", "create", "modify", "remove"]
+///   e.g. ["all"] or ["access", "create", "modify", "remove", "rescan"]
 /// * `f` - Function to handle the events, it receives an `Event` object
 /// * `num_threads` - Number of threads to use, if 1 it will run synchronously, if greater than 1 it will run asynchronously
 ///
@@ -58,15 +76,53 @@ static SHOULD_STOP: AtomicBool = AtomicBool::new(false);
 ///
 /// Watch::new(&path, recursive, &events, f, 1).start();
 /// ```
-
+///
+/// **Poll a network mount and watch a local directory natively**
+///
+/// ```no_run
+/// use std::path::Path;
+/// use std::time::Duration;
+/// use notify::Event;
+/// use std::sync::Arc;
+/// use watchcrab::backend::Backend;
+/// use watchcrab::watch::Watch;
+///
+/// let events = vec!["all".to_string()];
+/// let f = Arc::new(Box::new(move |event: Event| {
+///    println!("{:?}", event);
+/// }) as Box<dyn Fn(Event) + Send + Sync + 'static>);
+///
+/// Watch::new(Path::new("/mnt/nfs/share"), true, &events, f, 1)
+///     .backend(Backend::Poll)
+///     .poll_interval(Duration::from_secs(5))
+///     .add_root(Path::new("./"), Backend::Native)
+///     .start();
+/// ```
 pub struct Watch<'a> {
-    path: &'a Path,
+    roots: Vec<(&'a Path, Backend)>,
+    poll: PollOptions,
+    initial_scan: bool,
+    state_file: Option<&'a Path>,
+    state_interval: Duration,
+    hash_contents: bool,
+    rescan_on_overflow: bool,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
     recursive: bool,
     events: &'a Vec<String>,
-    f: Arc<Box<dyn Fn(Event) + Send + Sync + 'static>>,
+    f: Handler,
+    on_error: ErrorCallback,
+    reporter: Reporter,
+    max_consecutive_failures: Option<u64>,
+    handler_stats: Arc<HandlerStats>,
+    layers: Vec<Box<dyn Layer>>,
     #[allow(dead_code)]
     num_threads: usize, // is used in the constructor for initializing the thread pool
-    pool: Option<ThreadPool>,
+    pool: Option<KeyedExecutor>,
+    // Bounds the number of events queued in the thread pool, a permit is taken before queuing an event and released once it's handled
+    permits: Option<(Sender<()>, Receiver<()>)>,
+    #[cfg(feature = "testing")]
+    fake_backend: Option<FakeBackend>,
 }
 
 impl<'a> Watch<'a> {
@@ -77,18 +133,277 @@ impl<'a> Watch<'a> {
         f: Arc<Box<dyn Fn(Event) + Send + Sync + 'static>>,
         num_threads: usize,
     ) -> Watch<'a> {
-        Watch {
+        Watch::new_fallible(
             path,
             recursive,
             events,
-            f,
+            move |event: Event| -> Result<(), HandlerError> {
+                f(event);
+                Ok(())
+            },
+            num_threads,
+        )
+    }
+
+    /// Same as `Watch::new` with a handler returning a `Result`
+    ///
+    /// Errors returned by the handler are passed to the error callback (see `Watch::on_error`) and counted in the handler stats.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    /// use notify::Event;
+    /// use watchcrab::watch::Watch;
+    ///
+    /// let events = vec!["create".to_string()];
+    /// Watch::new_fallible(Path::new("./"), false, &events, |event: Event| {
+    ///     let path = event.paths.first().ok_or("event without path")?;
+    ///     std::fs::copy(path, "/backup/latest")?;
+    ///     Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+    /// }, 1)
+    /// .on_error(|event, failure| eprintln!("Backup of {:?} failed: {}", event.paths, failure))
+    /// .max_consecutive_failures(10)
+    /// .start();
+    /// ```
+    pub fn new_fallible<F, E>(
+        path: &'a Path,
+        recursive: bool,
+        events: &'a Vec<String>,
+        f: F,
+        num_threads: usize,
+    ) -> Watch<'a>
+    where
+        F: Fn(Event) -> Result<(), E> + Send + Sync + 'static,
+        E: Into<HandlerError>,
+    {
+        Watch {
+            roots: vec![(path, Backend::default())],
+            poll: PollOptions::default(),
+            initial_scan: false,
+            state_file: None,
+            state_interval: DEFAULT_STATE_INTERVAL,
+            hash_contents: false,
+            rescan_on_overflow: false,
+            queue_capacity: 0,
+            overflow_policy: OverflowPolicy::default(),
+            recursive,
+            events,
+            f: Arc::new(move |event| f(event).map_err(Into::into)),
+            on_error: Arc::new(print_failure),
+            reporter: Reporter::default(),
+            max_consecutive_failures: None,
+            handler_stats: Arc::new(HandlerStats::default()),
+            layers: Vec::new(),
             num_threads,
             pool: if num_threads > 1 {
-                Some(ThreadPool::new(num_threads))
+                Some(KeyedExecutor::new(num_threads, SerializeBy::default()))
             } else {
                 None
             },
+            permits: None,
+            #[cfg(feature = "testing")]
+            fake_backend: None,
+        }
+    }
+
+    /// Watch without a handler, to consume the events with `Watch::events` or `Watch::stream`
+    ///
+    /// No thread pool is created and `Watch::start` ignores the events.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    /// use watchcrab::watch::Watch;
+    ///
+    /// let events = vec!["create".to_string()];
+    /// for event in Watch::source(Path::new("./"), true, &events).events().unwrap() {
+    ///     println!("{:?}", event);
+    /// }
+    /// ```
+    pub fn source(path: &'a Path, recursive: bool, events: &'a Vec<String>) -> Watch<'a> {
+        Watch::new_fallible(path, recursive, events, |_| Ok::<(), HandlerError>(()), 1)
+    }
+
+    /// Same as `Watch::new` with the events dispatched to the matching routes of a `Router` instead of a single handler
+    ///
+    /// See `Router` for an example.
+    pub fn with_router(
+        path: &'a Path,
+        recursive: bool,
+        events: &'a Vec<String>,
+        router: Router,
+        num_threads: usize,
+    ) -> Watch<'a> {
+        Watch::new_fallible(
+            path,
+            recursive,
+            events,
+            move |event| router.dispatch(event),
+            num_threads,
+        )
+    }
+
+    /// Set the backend used to watch the main path, `Backend::Native` by default
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.roots[0].1 = backend;
+        self
+    }
+
+    /// Watch every root with a fake backend receiving the events injected by the tests, see `testing::FakeBackend`
+    #[cfg(feature = "testing")]
+    pub fn fake_backend(mut self, fake_backend: &FakeBackend) -> Self {
+        self.fake_backend = Some(fake_backend.clone());
+        self
+    }
+
+    /// Watch an additional root with its own backend
+    ///
+    /// Events of every root are handled by the same function and filters.
+    pub fn add_root(mut self, path: &'a Path, backend: Backend) -> Self {
+        self.roots.push((path, backend));
+        self
+    }
+
+    /// Set the interval between two scans of the roots using the polling backend
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll.interval = interval;
+        self
+    }
+
+    /// Compare file contents in the polling backend to detect changes that keep the same modification time
+    pub fn compare_contents(mut self, compare_contents: bool) -> Self {
+        self.poll.compare_contents = compare_contents;
+        self
+    }
+
+    /// Handle the files already present in the roots as `create` events before handling live events
+    ///
+    /// The scan respects the recursive option and the events filter, and is done after the watchers are registered so no file is missed.
+    pub fn initial_scan(mut self, initial_scan: bool) -> Self {
+        self.initial_scan = initial_scan;
+        self
+    }
+
+    /// Persist the state of the roots to a file to catch up on the changes made while the watcher was stopped
+    ///
+    /// At startup the roots are compared with the saved state and synthetic `create`, `modify` and `remove` events are handled for every difference,
+    /// replacing the initial scan. A file enters the saved state once its event was handled, so the events still queued when the watcher
+    /// stops or crashes are handled again at the next start. The state is saved at startup, every `state_interval` from another thread
+    /// and on graceful shutdown, once the pending events were handled.
+    /// If the state file does not exist yet, it is created and the initial scan option applies.
+    pub fn state_file(mut self, state_file: &'a Path) -> Self {
+        self.state_file = Some(state_file);
+        self
+    }
+
+    /// Set the interval between two saves of the state file, 60 seconds by default
+    pub fn state_interval(mut self, state_interval: Duration) -> Self {
+        self.state_interval = state_interval;
+        self
+    }
+
+    /// Store a hash of the contents of each file in the state file to detect changes that keep the same size and modification time
+    pub fn hash_contents(mut self, hash_contents: bool) -> Self {
+        self.hash_contents = hash_contents;
+        self
+    }
+
+    /// Keep a snapshot of the roots to recover the events lost when the event queue overflows
+    ///
+    /// When the backend reports that events were missed (e.g. inotify queue overflow), the affected root is scanned again and
+    /// synthetic `create`, `modify` and `remove` events are handled for every difference with the snapshot.
+    /// The snapshot is taken at startup and updated with every live event.
+    ///
+    /// Whether or not this is enabled, handlers receive a rescan event (`Other` kind, `Rescan` flag and the root as path)
+    /// when the events filter is `all` or contains `rescan`.
+    pub fn rescan_on_overflow(mut self, rescan_on_overflow: bool) -> Self {
+        self.rescan_on_overflow = rescan_on_overflow;
+        self
+    }
+
+    /// Set the maximum number of events waiting to be handled, 0 for no limit (default)
+    ///
+    /// The limit applies to the event queue and, with more than one thread, separately to the events queued in the thread pool.
+    /// When the thread pool is full, events wait in the event queue, and when the event queue is full the overflow policy applies.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity;
+        self.permits = pool_permits(self.num_threads, queue_capacity);
+        self
+    }
+
+    /// Set what to do with an event when the event queue is full, `OverflowPolicy::Block` by default
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Set the key used to serialize the handling of events when running in a thread pool, `SerializeBy::None` by default
+    ///
+    /// Events sharing a key are handled one at a time in the order they were received, while events with different keys still run in parallel.
+    /// With `SerializeBy::Path`, two quick modifications of the same file never run their handlers concurrently or out of order.
+    pub fn serialize_by(mut self, serialize_by: SerializeBy) -> Self {
+        if let Some(pool) = &mut self.pool {
+            pool.set_serialize_by(serialize_by);
         }
+        self
+    }
+
+    /// Set the function called when the handler returns an error or panics, by default the failure is printed to stderr
+    pub fn on_error<F>(mut self, on_error: F) -> Self
+    where
+        F: Fn(&Event, &HandlerFailure) + Send + Sync + 'static,
+    {
+        self.on_error = Arc::new(on_error);
+        self
+    }
+
+    /// Set the function called with the events rejected by the events filter, with `SkipReason::Kind`
+    ///
+    /// The layers report the events they skip with their own callbacks, e.g. `Debounce::on_skipped`.
+    pub fn on_skipped<F>(mut self, on_skipped: F) -> Self
+    where
+        F: Fn(&Event, SkipReason) + Send + Sync + 'static,
+    {
+        self.reporter.on_skipped = Some(Arc::new(on_skipped));
+        self
+    }
+
+    /// Set the function called with each decision taken on the events before they reach the layers: `Decision::Received`,
+    /// then `Decision::Accepted` and `Decision::Dispatched`, or `Decision::Skipped`
+    ///
+    /// The layers report their own decisions with their own callbacks, e.g. `Debounce::on_trace`.
+    pub fn on_trace<F>(mut self, on_trace: F) -> Self
+    where
+        F: Fn(&Event, &Decision) + Send + Sync + 'static,
+    {
+        self.reporter.on_trace = Some(Arc::new(on_trace));
+        self
+    }
+
+    /// Stop the watcher after `max` consecutive handler failures, 0 to never stop (default)
+    ///
+    /// `Watch::start` then returns an error once the events already handled by the thread pool completed.
+    pub fn max_consecutive_failures(mut self, max: u64) -> Self {
+        self.max_consecutive_failures = if max == 0 { None } else { Some(max) };
+        self
+    }
+
+    /// Wrap the handler in a layer, see `Layer`
+    ///
+    /// The first layer added is the outermost one: it receives the events first and passes them to the next layer, the handler is called last.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer + 'static,
+    {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// Counters of the events handled successfully, failed and panicked, shared with the running watcher
+    pub fn handler_stats(&self) -> Arc<HandlerStats> {
+        Arc::clone(&self.handler_stats)
     }
 
     /// Starts watching the specified directory for filesystem events.
@@ -107,122 +422,329 @@ impl<'a> Watch<'a> {
     /// # Returns
     /// `Ok(())` if the watcher starts and stops without errors.
     pub fn start(&self) -> Result<(), Error> {
-        let (tx, rx) = unbounded();
+        let _span = info_span!("watch", path = %self.roots[0].0.display()).entered();
+        let mut session = Session::open(self.source_config())?;
+        let startup_events = session.startup()?;
 
-        let mut watcher = RecommendedWatcher::new(tx, Config::default()).unwrap();
+        let (dispatcher, failure_rx) = self.dispatcher(session.handled_state());
 
-        let recursive_mode = if self.recursive {
-            RecursiveMode::Recursive
-        } else {
-            RecursiveMode::NonRecursive
-        };
+        for event in startup_events {
+            self.process_event(&dispatcher, Ok(event));
+        }
 
-        watcher
-            .watch(self.path.canonicalize().unwrap().as_path(), recursive_mode)
-            .unwrap();
-
-        // Signal handling for graceful shutdown
-        #[cfg(unix)]
-        let signal_rx = {
-            let (signal_tx, signal_rx) = unbounded();
-            let mut signals = Signals::new(&[SIGINT, SIGTERM])?;
-            thread::spawn(move || {
-                for sig in signals.forever() {
-                    if sig == SIGINT || sig == SIGTERM {
-                        // Send signal to the main thread to stop the watcher
-                        let _ = signal_tx.send(());
-                        break;
-                    }
-                }
-            });
-            signal_rx
+        // Signal handling for graceful shutdown, the watches of a fake backend are stopped by `FakeBackend::close`
+        #[cfg(feature = "testing")]
+        let signals = match self.fake_backend {
+            Some(_) => None,
+            None => Some(SignalRelay::spawn()?),
         };
-
-        #[cfg(target_family = "windows")]
-        let signal_rx = {
-            let (signal_tx, signal_rx) = unbounded();
-            unsafe {
-                SetConsoleCtrlHandler(Some(console_handler), BOOL(1))
-                    .expect("Failed to set control handler");
-            }
-            thread::spawn(move || {
-                while !SHOULD_STOP.load(Ordering::SeqCst) {
-                    thread::sleep(Duration::from_millis(100));
-                }
-                let _ = signal_tx.send(()); // Notify main loop to stop
-            });
-            signal_rx
+        #[cfg(not(feature = "testing"))]
+        let signals = Some(SignalRelay::spawn()?);
+        let signal_rx = match &signals {
+            Some(signals) => signals.rx.clone(),
+            None => never(),
         };
 
-        loop {
+        // Announce the shutdown as soon as the signal arrives, the session then drains the pending events
+        let (stop_tx, stop_rx) = unbounded();
+        let (done_tx, done_rx) = bounded::<()>(0);
+        let relay = thread::spawn(move || {
             select! {
-            recv(rx) -> event_result => {
-                match event_result {
-                    Ok(event_result) => {
-                        process_event(event_result, &self.events, &self.f, &self.pool);
+                recv(signal_rx) -> signal => {
+                    if signal.is_ok() {
+                        info!("Termination signal received. Stopping the watcher... Waiting for ongoing tasks to complete...");
+                        let _ = stop_tx.send(());
                     }
-                    Err(_) => break, // Closed channel, exit the loop
                 }
-            }
-                recv(signal_rx) -> _ => {
-                    println!("Termination signal received. Stopping the watcher... Waiting for ongoing tasks to complete...");
-                    let _ = watcher.unwatch(self.path.canonicalize().unwrap().as_path());
-                    // Process pending events
-                    while let Ok(event_result) = rx.try_recv() {
-                        process_event(event_result, &self.events, &self.f, &self.pool);
+                recv(failure_rx) -> failure => {
+                    if failure.is_ok() {
+                        let _ = stop_tx.send(());
                     }
-                    break;
                 }
+                recv(done_rx) -> _ => {}
+            }
+        });
+
+        session.run(&stop_rx, |event_result| {
+            // Pending events are not handled once the handler failed too many times
+            if dispatcher.stopped() {
+                return false;
             }
+            self.process_event(&dispatcher, event_result);
+            true
+        });
+
+        // Stop the signal and relay threads, they still wait when the session stopped without a signal
+        drop(done_tx);
+        let _ = relay.join();
+        if let Some(signals) = signals {
+            signals.close();
         }
 
-        //wait for all threads to finish
+        // Wait for the thread pool and the layers before saving the state of the handled events
+        let result = self.finish(dispatcher);
+        session.close();
+        result
+    }
+
+    /// Handle the events of an iterator instead of watching the roots
+    ///
+    /// The events go through the same events filter, layers, thread pool and failure limit as the events of `Watch::start`,
+    /// but no watcher is registered: the roots and the other options of the event source are ignored.
+    /// The iterator paces the events, see `replay::Replay` to replay a recorded session with its timings.
+    /// Returns once every event was handled, the events still waiting in a layer (e.g. `Debounce`) are handled before.
+    ///
+    /// # Errors
+    /// Returns the first `Error` of the iterator, or an `Error` if the handler failed too many consecutive times
+    pub fn replay<I>(&self, events: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = Result<Event, Error>>,
+    {
+        let _span = info_span!("replay").entered();
+        let (dispatcher, _failure_rx) = self.dispatcher(None);
+
+        for event in events {
+            if dispatcher.stopped() {
+                break;
+            }
+            if let Err(e) = event.map(|event| self.process_event(&dispatcher, Ok(event))) {
+                self.finish(dispatcher)?;
+                return Err(e);
+            }
+        }
+        self.finish(dispatcher)
+    }
+
+    /// Wait for the events handled by the thread pool and the layers, see `Watch::replay`
+    ///
+    /// # Errors
+    /// Returns an `Error` if the handler failed too many consecutive times
+    pub(crate) fn finish(&self, dispatcher: Arc<Dispatcher>) -> Result<(), Error> {
         if let Some(pool) = &self.pool {
             pool.join();
         }
+        // Dropping the handler makes the layers handle the events they still hold, their failures count too
+        let accounting = dispatcher.accounting();
+        drop(dispatcher);
+        let stopped = accounting.stopped();
 
+        if stopped {
+            return Err(Error::other(format!(
+                "Stopped after {} consecutive handler failures",
+                self.handler_stats.consecutive_failures()
+            )));
+        }
         Ok(())
     }
+
+    /// Handler wrapped in the layers, and the receiver notified when it failed too many consecutive times
+    ///
+    /// With a `HandledState`, the events are recorded in it once the handler returned.
+    pub(crate) fn dispatcher(
+        &self,
+        handled: Option<HandledState>,
+    ) -> (Arc<Dispatcher>, Receiver<()>) {
+        let (failure_tx, failure_rx) = bounded(1);
+        let f = match handled {
+            Some(handled) => {
+                let f = Arc::clone(&self.f);
+                Arc::new(move |event: Event| {
+                    let paths = event.paths.clone();
+                    let result = f(event);
+                    handled.handled(&paths);
+                    result
+                })
+            }
+            None => Arc::clone(&self.f),
+        };
+        let mut dispatcher = Dispatcher::new(
+            f,
+            Arc::clone(&self.on_error),
+            Arc::clone(&self.handler_stats),
+            self.max_consecutive_failures,
+            failure_tx,
+        );
+        if !self.layers.is_empty() {
+            dispatcher = dispatcher.wrap(|handler| {
+                self.layers
+                    .iter()
+                    .rev()
+                    .fold(handler, |inner, layer| layer.layer(inner))
+            });
+        }
+        let dispatcher = Arc::new(dispatcher);
+        (dispatcher, failure_rx)
+    }
+
+    /// Owned copy of the settings of the event source
+    pub(crate) fn source_config(&self) -> SourceConfig {
+        SourceConfig {
+            roots: self
+                .roots
+                .iter()
+                .map(|(path, backend)| (path.to_path_buf(), *backend))
+                .collect(),
+            recursive: self.recursive,
+            poll: self.poll,
+            initial_scan: self.initial_scan,
+            state_file: self.state_file.map(Path::to_path_buf),
+            state_interval: self.state_interval,
+            hash_contents: self.hash_contents,
+            rescan_on_overflow: self.rescan_on_overflow,
+            queue_capacity: self.queue_capacity,
+            overflow_policy: self.overflow_policy,
+            #[cfg(feature = "testing")]
+            fake_backend: self.fake_backend.clone(),
+        }
+    }
+
+    /// Events filter of the watch
+    pub(crate) fn events_filter(&self) -> &[String] {
+        self.events
+    }
+
+    /// Filter an event and dispatch it to the handler, in the thread pool if there is one
+    pub(crate) fn process_event(
+        &self,
+        dispatcher: &Arc<Dispatcher>,
+        event_result: Result<Event, notify::Error>,
+    ) {
+        process_event(
+            event_result,
+            self.events,
+            &self.reporter,
+            dispatcher,
+            &self.pool,
+            &self.permits,
+        );
+    }
+}
+
+/// Permits bounding the events queued in the thread pool, `None` without thread pool or without limit
+fn pool_permits(num_threads: usize, capacity: usize) -> Option<(Sender<()>, Receiver<()>)> {
+    if num_threads > 1 && capacity > 0 {
+        Some(bounded(capacity))
+    } else {
+        None
+    }
 }
 
 fn process_event(
     event_result: Result<Event, notify::Error>,
-    events_filter: &Vec<String>,
-    handler: &Arc<Box<dyn Fn(Event) + Send + Sync + 'static>>,
-    pool: &Option<ThreadPool>,
+    events_filter: &[String],
+    reporter: &Reporter,
+    dispatcher: &Arc<Dispatcher>,
+    pool: &Option<KeyedExecutor>,
+    permits: &Option<(Sender<()>, Receiver<()>)>,
 ) {
     match event_result {
         Ok(event) => {
-            let kind_str = if events_filter == &["all"] {
-                "all"
-            } else if event.kind.is_access() {
-                "access"
-            } else if event.kind.is_create() {
-                "create"
-            } else if event.kind.is_modify() {
-                "modify"
-            } else if event.kind.is_remove() {
-                "remove"
-            } else {
+            reporter.report(&event, Decision::Received);
+            if !matches_filter(&event, events_filter) {
+                debug!(kind = kind_name(&event), paths = ?event.paths, "Event filtered out");
+                reporter.report(&event, Decision::Skipped(SkipReason::Kind));
                 return;
-            };
-
-            let kind_str = String::from(kind_str);
-
-            if kind_str == "all" || events_filter.contains(&kind_str) {
-                if let Some(pool) = pool {
-                    let f = Arc::clone(handler);
-                    pool.execute(move || {
-                        f(event.clone());
-                    });
+            }
+            debug!(kind = kind_name(&event), paths = ?event.paths, "Event dispatched");
+            if reporter.tracing() {
+                let filter = if events_filter == ["all"] {
+                    "all"
                 } else {
-                    handler(event)
-                }
+                    kind_name(&event)
+                };
+                reporter.report(&event, Decision::Accepted(filter));
+                let target = match pool {
+                    Some(pool) => Target::Pool {
+                        key: pool.serialize_by().key(&event),
+                    },
+                    None => Target::Inline,
+                };
+                reporter.report(&event, Decision::Dispatched(target));
+            }
+
+            if let Some(pool) = pool {
+                let dispatcher = Arc::clone(dispatcher);
+                let span = Span::current();
+                // Wait for room in the thread pool, the events keep queuing in the event queue meanwhile
+                let permit = permits.as_ref().map(|(permit_tx, permit_rx)| {
+                    let _ = permit_tx.send(());
+                    permit_rx.clone()
+                });
+                pool.execute(&event.clone(), move || {
+                    let _entered = span.enter();
+                    dispatcher.call(event);
+                    if let Some(permit) = permit {
+                        let _ = permit.try_recv();
+                    }
+                });
+            } else {
+                dispatcher.call(event)
             }
         }
         Err(e) => {
-            println!("Watch error: {:?}", e);
+            error!(error = %e, "Watch error");
+        }
+    }
+}
+
+/// Thread sending a message to `rx` on the first termination signal (SIGINT or SIGTERM on Unix, Ctrl+C or close event on Windows)
+struct SignalRelay {
+    rx: Receiver<()>,
+    #[cfg(target_family = "unix")]
+    handle: signal_hook::iterator::Handle,
+    #[cfg(target_family = "windows")]
+    closed: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl SignalRelay {
+    #[cfg(target_family = "unix")]
+    fn spawn() -> Result<SignalRelay, Error> {
+        let (signal_tx, rx) = unbounded();
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let handle = signals.handle();
+        let thread = thread::spawn(move || {
+            for sig in signals.forever() {
+                if sig == SIGINT || sig == SIGTERM {
+                    // Send signal to the main thread to stop the watcher
+                    let _ = signal_tx.send(());
+                    break;
+                }
+            }
+        });
+        Ok(SignalRelay { rx, handle, thread })
+    }
+
+    #[cfg(target_family = "windows")]
+    fn spawn() -> Result<SignalRelay, Error> {
+        let (signal_tx, rx) = unbounded();
+        let closed = Arc::new(AtomicBool::new(false));
+        unsafe {
+            SetConsoleCtrlHandler(Some(console_handler), BOOL(1))
+                .expect("Failed to set control handler");
         }
+        let thread = thread::spawn({
+            let closed = Arc::clone(&closed);
+            move || {
+                while !SHOULD_STOP.load(Ordering::SeqCst) {
+                    if closed.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+                let _ = signal_tx.send(()); // Notify main loop to stop
+            }
+        });
+        Ok(SignalRelay { rx, closed, thread })
+    }
+
+    /// Stop waiting for the signals and join the thread
+    fn close(self) {
+        #[cfg(target_family = "unix")]
+        self.handle.close();
+        #[cfg(target_family = "windows")]
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.thread.join();
     }
 }
 
@@ -0,0 +1,608 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::{after, bounded, never, select, tick, Receiver, Sender, TrySendError};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{debug, error, warn};
+
+/// Default timeout of a request, connection included
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default number of retries of a failed request before it is dropped, or sent again every 30 seconds when spooled
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// Default delay before the first retry, doubled on each retry
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Header carrying the HMAC-SHA256 signature of the body, as `sha256=<hex>`
+pub const SIGNATURE_HEADER: &str = "X-Watchcrab-Signature";
+
+/// Longest delay between two retries
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Interval between two attempts to deliver the spooled requests once their retries are exhausted
+const SPOOL_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of record batches waiting for the delivery thread before new records are dropped
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Interval between two reports of the records dropped because the delivery thread fell behind
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Options of a webhook
+///
+/// * `headers` - Headers added to every request, `Content-Type: application/json` is always sent
+/// * `secret` - Key of the HMAC-SHA256 signature of the body, sent in the `X-Watchcrab-Signature` header
+/// * `batch_size` - Maximum number of records per request, 1 to post each record as is, otherwise records are posted as a JSON array
+/// * `timeout` - Timeout of a request
+/// * `max_retries` - Number of retries of a failed request, with an exponential backoff starting at `backoff`
+/// * `spool_dir` - Directory keeping the failed requests and the ones behind them, they are sent again once the endpoint is back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookOptions {
+    pub headers: Vec<(String, String)>,
+    pub secret: Option<String>,
+    pub batch_size: usize,
+    pub timeout: Duration,
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub spool_dir: Option<PathBuf>,
+}
+
+impl Default for WebhookOptions {
+    fn default() -> Self {
+        WebhookOptions {
+            headers: Vec::new(),
+            secret: None,
+            batch_size: 1,
+            timeout: DEFAULT_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            spool_dir: None,
+        }
+    }
+}
+
+enum Message {
+    Records(Vec<String>),
+    Flush(Sender<()>),
+}
+
+/// Handle to a thread posting records to an HTTP endpoint
+///
+/// Records are JSON documents, like the records of the command line tool. Requests are delivered in order: once a request
+/// failed, the new ones wait behind it, in the spool directory if there is one, and are sent when its retry succeeds.
+/// Sending records never blocks: when the delivery thread falls behind, the new records are dropped and counted,
+/// see `Webhook::dropped`. The number of dropped records is reported on stderr every 10 seconds and on shutdown.
+///
+/// # Examples
+///
+/// ```no_run
+/// use watchcrab::sink::{LogWriter, Sink, WriterOptions};
+/// use watchcrab::webhook::{Webhook, WebhookOptions};
+///
+/// let options = WebhookOptions {
+///     secret: Some(String::from("shared secret")),
+///     batch_size: 50,
+///     spool_dir: Some("/var/spool/watchcrab".into()),
+///     ..WebhookOptions::default()
+/// };
+/// let webhook = Webhook::spawn("https://hooks.example.com/files", options).unwrap();
+/// let writer = LogWriter::spawn(vec![Sink::Stdout, Sink::Webhook(webhook)], WriterOptions::default());
+/// writer.write(String::from(r#"{"Kind": "Create(File)", "Path": "/tmp/new.txt"}"#));
+/// writer.flush();
+/// ```
+pub struct Webhook {
+    tx: Sender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Webhook {
+    /// Start the delivery thread of a webhook
+    ///
+    /// # Errors
+    /// Returns an `Error` if the URL is not an http or https URL or the spool directory can't be created
+    pub fn spawn(url: &str, options: WebhookOptions) -> Result<Webhook, Error> {
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Invalid webhook URL '{}', expected an http or https URL",
+                    url
+                ),
+            ));
+        }
+        if let Some(spool_dir) = &options.spool_dir {
+            fs::create_dir_all(spool_dir)?;
+        }
+
+        let (tx, rx) = bounded(QUEUE_CAPACITY);
+        let dropped = Arc::new(AtomicU64::new(0));
+        // The requests spooled by a previous run are sent first, right away
+        let backlog: VecDeque<Pending> = spooled(options.spool_dir.as_deref())
+            .into_iter()
+            .map(Pending::Spooled)
+            .collect();
+        let delivery = Delivery {
+            agent: ureq::AgentBuilder::new().timeout(options.timeout).build(),
+            url: url.to_string(),
+            retry_at: (!backlog.is_empty()).then(Instant::now),
+            backlog,
+            attempts: 0,
+            backoff: options.backoff,
+            options,
+            sequence: 0,
+            dropped: Arc::clone(&dropped),
+            reported_dropped: 0,
+        };
+        thread::spawn(move || delivery.run(rx));
+        Ok(Webhook { tx, dropped })
+    }
+
+    /// Queue records to post, they are dropped if the delivery thread fell behind
+    pub fn send(&self, records: Vec<String>) {
+        let count = records.len() as u64;
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(Message::Records(records)) {
+            self.dropped.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of records dropped because the delivery thread fell behind, since the webhook started
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the records queued before are delivered, waiting for a retry or dropped
+    pub fn flush(&self) {
+        let (ack_tx, ack_rx) = bounded(1);
+        if self.tx.send(Message::Flush(ack_tx)).is_ok() {
+            let _ = ack_rx.recv();
+        }
+    }
+}
+
+/// Why a request failed
+enum Failure {
+    /// The endpoint may accept the request later (connection error, timeout, 5xx, 408 or 429)
+    Transient(String),
+    /// The endpoint rejected the request, sending it again won't help
+    Permanent(String),
+}
+
+/// Request waiting behind a failed one, on disk when there is a spool directory
+enum Pending {
+    Spooled(PathBuf),
+    Memory(String),
+}
+
+struct Delivery {
+    agent: ureq::Agent,
+    url: String,
+    options: WebhookOptions,
+    // Requests waiting for the first of them to be delivered, oldest first, read from the spool directory at startup
+    backlog: VecDeque<Pending>,
+    // Failed attempts of the first request of the backlog, and delay before its next one
+    attempts: u32,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    sequence: u64,
+    dropped: Arc<AtomicU64>,
+    reported_dropped: u64,
+}
+
+impl Delivery {
+    fn run(mut self, rx: Receiver<Message>) {
+        let stats_ticker = tick(STATS_INTERVAL);
+        loop {
+            // The retries are only sent from here, a flush never waits for the backoff
+            let retry = match self.retry_at {
+                Some(at) => after(at.saturating_duration_since(Instant::now())),
+                None => never(),
+            };
+            select! {
+                recv(rx) -> message => match message {
+                    Ok(Message::Records(records)) => {
+                        for body in self.bodies(records) {
+                            self.deliver(body);
+                        }
+                    }
+                    Ok(Message::Flush(ack)) => {
+                        let _ = ack.send(());
+                    }
+                    Err(_) => {
+                        self.report_dropped();
+                        self.report_lost();
+                        return;
+                    }
+                },
+                recv(retry) -> _ => self.retry(),
+                recv(stats_ticker) -> _ => self.report_dropped(),
+            }
+        }
+    }
+
+    fn report_dropped(&mut self) {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped != self.reported_dropped {
+            warn!(
+                url = %self.url,
+                dropped = dropped - self.reported_dropped,
+                total_dropped = dropped,
+                "Webhook queue full, records were dropped since the last report"
+            );
+            self.reported_dropped = dropped;
+        }
+    }
+
+    /// Report the requests of the backlog kept in memory, lost on shutdown
+    fn report_lost(&self) {
+        let lost = self
+            .backlog
+            .iter()
+            .filter(|pending| matches!(pending, Pending::Memory(_)))
+            .count();
+        if lost > 0 {
+            error!(url = %self.url, lost, "Webhook stopped before delivering requests, no spool directory");
+        }
+    }
+
+    /// Group records into request bodies
+    fn bodies(&self, records: Vec<String>) -> Vec<String> {
+        if self.options.batch_size <= 1 {
+            return records;
+        }
+        records
+            .chunks(self.options.batch_size)
+            .map(|chunk| format!("[{}]", chunk.join(",")))
+            .collect()
+    }
+
+    /// Post a body once, a failed request goes to the backlog and is retried later
+    fn deliver(&mut self, body: String) {
+        // Keep the order of the requests: while a request waits for a retry, the new ones wait behind it
+        if !self.backlog.is_empty() {
+            self.push(body);
+            return;
+        }
+
+        match self.post(&body) {
+            Ok(()) => {}
+            Err(Failure::Permanent(reason)) => {
+                error!(url = %self.url, %reason, "Webhook rejected the request, dropping it");
+            }
+            Err(Failure::Transient(reason)) => {
+                if self.push(body) {
+                    self.schedule_retry(&reason);
+                }
+            }
+        }
+    }
+
+    /// Send the requests of the backlog, in order, until one fails
+    fn retry(&mut self) {
+        while let Some(pending) = self.backlog.front() {
+            let body = match pending {
+                Pending::Memory(body) => body.clone(),
+                Pending::Spooled(path) => match fs::read_to_string(path) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        error!(path = %path.display(), error = %e, "Unable to read spooled webhook request, dropping it");
+                        self.pop();
+                        continue;
+                    }
+                },
+            };
+            match self.post(&body) {
+                Ok(()) => self.pop(),
+                Err(Failure::Permanent(reason)) => {
+                    error!(url = %self.url, %reason, "Webhook rejected the request, dropping it");
+                    self.pop();
+                }
+                Err(Failure::Transient(reason)) => {
+                    self.schedule_retry(&reason);
+                    return;
+                }
+            }
+        }
+        self.retry_at = None;
+    }
+
+    /// Plan the next attempt of the first request of the backlog after a transient failure
+    ///
+    /// Once the retries are exhausted, a spooled request is sent again every 30 seconds, a request kept in memory is
+    /// dropped.
+    fn schedule_retry(&mut self, reason: &str) {
+        self.attempts += 1;
+        if self.attempts <= self.options.max_retries {
+            warn!(url = %self.url, %reason, attempt = self.attempts, backoff = ?self.backoff, "Webhook request failed, retrying");
+            self.retry_at = Some(Instant::now() + self.backoff);
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            return;
+        }
+        match self.backlog.front() {
+            Some(Pending::Spooled(_)) => {
+                error!(url = %self.url, %reason, "Webhook request failed, keeping the spool until the endpoint is back");
+                self.retry_at = Some(Instant::now() + SPOOL_RETRY_INTERVAL);
+            }
+            _ => {
+                error!(url = %self.url, %reason, "Webhook request failed, dropping it");
+                self.pop();
+                self.retry_at = (!self.backlog.is_empty()).then(Instant::now);
+            }
+        }
+    }
+
+    /// Remove the first request of the backlog, the next one starts with a fresh backoff
+    fn pop(&mut self) {
+        if let Some(Pending::Spooled(path)) = self.backlog.pop_front() {
+            let _ = fs::remove_file(path);
+        }
+        self.attempts = 0;
+        self.backoff = self.options.backoff;
+    }
+
+    /// Add a body at the end of the backlog, in the spool directory if there is one
+    ///
+    /// Returns false if the body was dropped.
+    fn push(&mut self, body: String) -> bool {
+        let Some(spool_dir) = &self.options.spool_dir else {
+            if self.backlog.len() >= QUEUE_CAPACITY {
+                error!(url = %self.url, "Webhook request dropped, too many requests waiting for a retry and no spool directory");
+                return false;
+            }
+            self.backlog.push_back(Pending::Memory(body));
+            return true;
+        };
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos())
+            .unwrap_or(0);
+        self.sequence += 1;
+        let path = spool_dir.join(format!("{:024}-{:08}.json", nanos, self.sequence));
+        match write_atomic(&path, &body) {
+            Ok(()) => {
+                warn!(path = %path.display(), "Webhook request spooled");
+                self.backlog.push_back(Pending::Spooled(path));
+                true
+            }
+            Err(e) => {
+                error!(path = %path.display(), error = %e, "Unable to spool webhook request, dropping it");
+                false
+            }
+        }
+    }
+
+    fn post(&self, body: &str) -> Result<(), Failure> {
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json");
+        for (name, value) in &self.options.headers {
+            request = request.set(name, value);
+        }
+        if let Some(secret) = &self.options.secret {
+            request = request.set(SIGNATURE_HEADER, &sign(secret, body));
+        }
+
+        match request.send_string(body) {
+            Ok(response) => {
+                debug!(url = %self.url, status = response.status(), "Webhook request delivered");
+                Ok(())
+            }
+            Err(ureq::Error::Status(status, _))
+                if status == 408 || status == 429 || status >= 500 =>
+            {
+                Err(Failure::Transient(format!("status {}", status)))
+            }
+            Err(ureq::Error::Status(status, _)) => {
+                Err(Failure::Permanent(format!("status {}", status)))
+            }
+            Err(e) => Err(Failure::Transient(e.to_string())),
+        }
+    }
+}
+
+/// Request files of a spool directory, oldest first
+fn spooled(spool_dir: Option<&Path>) -> VecDeque<PathBuf> {
+    let Some(spool_dir) = spool_dir else {
+        return VecDeque::new();
+    };
+    let mut files: Vec<PathBuf> = match fs::read_dir(spool_dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "json")
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    files.into()
+}
+
+/// Parse a `Name: value` header
+///
+/// # Errors
+/// Returns an error message if the header has no `:` or an empty name
+pub fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!(
+            "Invalid header '{}', expected the format: <name>: <value>",
+            header
+        )),
+    }
+}
+
+/// HMAC-SHA256 signature of a body, as sent in the `X-Watchcrab-Signature` header
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", signature)
+}
+
+/// Write a spool file under a temporary name first, so a partial file is never replayed
+fn write_atomic(path: &Path, body: &str) -> Result<(), Error> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, body)?;
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Answer the next requests with the given statuses, returning the headers and body of each request
+    fn serve(statuses: Vec<u16>) -> (String, thread::JoinHandle<Vec<(String, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:")
+                    {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                requests.push((head, String::from_utf8(body).unwrap()));
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_batches_are_signed_and_retried() {
+        let (url, server) = serve(vec![503, 200]);
+        let options = WebhookOptions {
+            secret: Some(String::from("secret")),
+            batch_size: 2,
+            backoff: Duration::from_millis(10),
+            ..WebhookOptions::default()
+        };
+        let webhook = Webhook::spawn(&url, options).unwrap();
+        webhook.send(vec![String::from(r#"{"a":1}"#), String::from(r#"{"b":2}"#)]);
+        webhook.flush();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (head, body) = &requests[1];
+        assert_eq!(body, r#"[{"a":1},{"b":2}]"#);
+        assert!(head
+            .to_ascii_lowercase()
+            .contains(&format!("x-watchcrab-signature: {}", sign("secret", body))));
+    }
+
+    #[test]
+    fn test_send_never_blocks_on_a_hanging_endpoint() {
+        // The connections are accepted by the OS but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let options = WebhookOptions {
+            max_retries: 0,
+            ..WebhookOptions::default()
+        };
+        let webhook = Webhook::spawn(&url, options).unwrap();
+
+        // The delivery thread holds at most one batch, the queue the next ones
+        for i in 0..QUEUE_CAPACITY + 10 {
+            webhook.send(vec![i.to_string()]);
+        }
+        assert!(webhook.dropped() >= 9);
+    }
+
+    #[test]
+    fn test_spooled_requests_are_delivered_first() {
+        let spool_dir = TempDir::new("spool");
+        let (url, server) = serve(vec![500, 200, 200]);
+        let options = WebhookOptions {
+            max_retries: 1,
+            backoff: Duration::from_millis(300),
+            spool_dir: Some(spool_dir.to_path_buf()),
+            ..WebhookOptions::default()
+        };
+        let webhook = Webhook::spawn(&url, options).unwrap();
+        webhook.send(vec![String::from("1")]);
+        webhook.flush();
+        assert_eq!(fs::read_dir(&spool_dir).unwrap().count(), 1);
+
+        // Spooled behind the first request without being posted, until the retry
+        webhook.send(vec![String::from("2")]);
+        webhook.flush();
+        assert_eq!(fs::read_dir(&spool_dir).unwrap().count(), 2);
+
+        let bodies: Vec<String> = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|(_, body)| body)
+            .collect();
+        assert_eq!(bodies, vec!["1", "1", "2"]);
+        webhook.flush();
+        assert_eq!(fs::read_dir(&spool_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_hanging_endpoint_delays_only_the_first_request() {
+        // The connections are accepted by the OS but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let spool_dir = TempDir::new("hanging-spool");
+        let options = WebhookOptions {
+            timeout: Duration::from_millis(200),
+            backoff: Duration::from_secs(60),
+            spool_dir: Some(spool_dir.to_path_buf()),
+            ..WebhookOptions::default()
+        };
+        let webhook = Webhook::spawn(&url, options).unwrap();
+
+        let start = Instant::now();
+        for i in 0..20 {
+            webhook.send(vec![i.to_string()]);
+            webhook.flush();
+        }
+        // One request timed out, the others went straight to the spool and no flush waited for a retry
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(fs::read_dir(&spool_dir).unwrap().count(), 20);
+        assert_eq!(webhook.dropped(), 0);
+    }
+}
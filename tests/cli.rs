@@ -0,0 +1,363 @@
+//! End-to-end tests of the command line tool, run against temporary directories
+#![cfg(unix)]
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{unbounded, Receiver};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Temporary directory removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let dir =
+            std::env::temp_dir().join(format!("watchcrab-cli-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("watched")).unwrap();
+        TempDir(dir.canonicalize().unwrap())
+    }
+
+    /// Directory watched by the tests
+    fn watched(&self) -> PathBuf {
+        self.0.join("watched")
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Running watchcrab binary, with its stdout and stderr read line by line
+struct Watchcrab {
+    child: Child,
+    stdout: Receiver<String>,
+    stderr: Receiver<String>,
+}
+
+impl Watchcrab {
+    /// Start watchcrab and wait until it watches the directory
+    fn start(args: &[&str]) -> Watchcrab {
+        let watchcrab = Watchcrab::spawn(args);
+        watchcrab.wait_stderr("Watching");
+        watchcrab
+    }
+
+    /// Start watchcrab without waiting for it, when it logs nothing
+    fn spawn(args: &[&str]) -> Watchcrab {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_watchcrab"))
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        Watchcrab {
+            stdout: lines(child.stdout.take().unwrap()),
+            stderr: lines(child.stderr.take().unwrap()),
+            child,
+        }
+    }
+
+    /// Wait for a line of stderr containing `text`
+    fn wait_stderr(&self, text: &str) -> String {
+        loop {
+            let line = self
+                .stderr
+                .recv_timeout(TIMEOUT)
+                .unwrap_or_else(|_| panic!("watchcrab did not log '{}'", text));
+            if line.contains(text) {
+                return line;
+            }
+        }
+    }
+
+    /// Wait for `count` lines of stdout
+    fn stdout_lines(&self, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| {
+                self.stdout
+                    .recv_timeout(TIMEOUT)
+                    .expect("missing stdout line")
+            })
+            .collect()
+    }
+
+    /// Stop watchcrab like Ctrl+C and wait for it to exit
+    fn interrupt(mut self) -> (ExitStatus, Vec<String>) {
+        unsafe {
+            libc::kill(self.child.id() as libc::pid_t, libc::SIGINT);
+        }
+        let status = self.child.wait().unwrap();
+        (status, self.stdout.iter().collect())
+    }
+}
+
+/// Lines of a pipe, read by a thread until the pipe is closed
+fn lines<R: std::io::Read + Send + 'static>(pipe: R) -> Receiver<String> {
+    let (tx, rx) = unbounded();
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Wait until a file has `count` lines
+fn wait_file_lines(path: &Path, count: usize) -> Vec<String> {
+    let start = Instant::now();
+    loop {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        let lines: Vec<String> = content.lines().map(String::from).collect();
+        if lines.len() >= count || start.elapsed() > TIMEOUT {
+            return lines;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn test_default_json_output() {
+    let dir = TempDir::new("json");
+    let watched = dir.watched();
+    let watchcrab = Watchcrab::start(&["--path", watched.to_str().unwrap(), "--events", "create"]);
+
+    fs::write(watched.join("new.txt"), "content").unwrap();
+    let lines = watchcrab.stdout_lines(1);
+    assert_eq!(
+        lines[0],
+        format!(
+            r#"{{"Kind": "Create(File)", "Path": "{}"}}"#,
+            watched.join("new.txt").display()
+        )
+    );
+
+    let (status, _) = watchcrab.interrupt();
+    assert!(status.success());
+}
+
+#[test]
+fn test_output_file_and_threads() {
+    let dir = TempDir::new("output");
+    let watched = dir.watched();
+    let output = dir.0.join("records.jsonl");
+    let watchcrab = Watchcrab::start(&[
+        "--path",
+        watched.to_str().unwrap(),
+        "--events",
+        "create",
+        "--threads",
+        "4",
+        "--args",
+        "echo {path}",
+        "--output",
+        output.to_str().unwrap(),
+    ]);
+
+    for i in 0..8 {
+        fs::write(watched.join(format!("{}.txt", i)), "").unwrap();
+    }
+    let mut records = wait_file_lines(&output, 8);
+    let (status, stdout) = watchcrab.interrupt();
+    assert!(status.success());
+    assert!(stdout.is_empty());
+
+    records.sort();
+    let expected: Vec<String> = (0..8)
+        .map(|i| {
+            format!(
+                r#"{{"stdout": "{}", "stderr": ""}}"#,
+                watched.join(format!("{}.txt", i)).display()
+            )
+        })
+        .collect();
+    assert_eq!(records, expected);
+}
+
+#[test]
+fn test_graceful_shutdown_drains_pending_events() {
+    let dir = TempDir::new("shutdown");
+    let watched = dir.watched();
+    let watchcrab = Watchcrab::start(&[
+        "--path",
+        watched.to_str().unwrap(),
+        "--events",
+        "create",
+        "--args",
+        "sleep 0.2; echo {path}",
+    ]);
+
+    for name in ["a", "b", "c"] {
+        fs::write(watched.join(name), "").unwrap();
+    }
+    // Once the first command finished, the other events are still running or queued when the signal arrives
+    let first = watchcrab.stdout_lines(1);
+    let (status, rest) = watchcrab.interrupt();
+    assert!(status.success());
+
+    let outputs: Vec<String> = first.into_iter().chain(rest).collect();
+    let expected: Vec<String> = ["a", "b", "c"]
+        .iter()
+        .map(|name| {
+            format!(
+                r#"{{"stdout": "{}", "stderr": ""}}"#,
+                watched.join(name).display()
+            )
+        })
+        .collect();
+    assert_eq!(outputs, expected);
+}
+
+#[test]
+fn test_dry_run_writes_the_command_without_running_it() {
+    let dir = TempDir::new("dry-run");
+    let watched = dir.watched();
+    let watchcrab = Watchcrab::start(&[
+        "--path",
+        watched.to_str().unwrap(),
+        "--events",
+        "create",
+        "--args",
+        "touch {path}.ran; echo $PATH",
+        "--dry-run",
+    ]);
+
+    let path = watched.join("new.txt");
+    fs::write(&path, "").unwrap();
+    // The modification of the file is written too, as skipped by the kind filter
+    let record: serde_json::Value = loop {
+        let line = watchcrab
+            .stdout
+            .recv_timeout(TIMEOUT)
+            .expect("missing dry-run record");
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        if record["dry_run"] == "run" {
+            break record;
+        }
+        assert_eq!(record["dry_run"], "skip");
+    };
+    let (status, _) = watchcrab.interrupt();
+    assert!(status.success());
+
+    assert_eq!(record["kind"], "create");
+    assert_eq!(record["detail"], "Create(File)");
+    assert_eq!(
+        record["command"],
+        format!("touch {}.ran; echo $PATH", path.display())
+    );
+    // Only the variables referenced by the command are written
+    let env = record["env"].as_object().unwrap();
+    assert_eq!(env.keys().collect::<Vec<_>>(), ["PATH"]);
+    assert!(!watched.join("new.txt.ran").exists());
+}
+
+#[test]
+fn test_explain_logs_each_decision() {
+    let dir = TempDir::new("explain");
+    let watched = dir.watched();
+    let watchcrab = Watchcrab::start(&[
+        "--path",
+        watched.to_str().unwrap(),
+        "--events",
+        "create",
+        "--explain",
+    ]);
+
+    fs::write(watched.join("new.txt"), "").unwrap();
+    for decision in [
+        "received",
+        "accepted by the events filter (create)",
+        "dispatched to the handler",
+    ] {
+        let line = watchcrab.wait_stderr(&format!("watchcrab::explain: {}", decision));
+        assert!(line.contains("kind=create detail=Create(File)"), "{}", line);
+    }
+    let (status, stdout) = watchcrab.interrupt();
+    assert!(status.success());
+    // The explanations stay on stderr, stdout only has the record
+    assert_eq!(
+        stdout,
+        [format!(
+            r#"{{"Kind": "Create(File)", "Path": "{}"}}"#,
+            watched.join("new.txt").display()
+        )]
+    );
+}
+
+#[test]
+fn test_explain_silenced_by_log_level_off() {
+    let dir = TempDir::new("explain-off");
+    let watched = dir.watched();
+    let watchcrab = Watchcrab::spawn(&[
+        "--path",
+        watched.to_str().unwrap(),
+        "--events",
+        "create",
+        "--explain",
+        "--log-level",
+        "off",
+    ]);
+
+    // Nothing tells when the directory is watched, files are created until one is recorded
+    let start = Instant::now();
+    let mut created = 0;
+    while watchcrab.stdout.is_empty() && start.elapsed() < TIMEOUT {
+        fs::write(watched.join(format!("{}.txt", created)), "").unwrap();
+        created += 1;
+        thread::sleep(Duration::from_millis(50));
+    }
+    let stderr = watchcrab.stderr.clone();
+    let (status, stdout) = watchcrab.interrupt();
+    assert!(status.success());
+    assert!(!stdout.is_empty());
+    assert_eq!(stderr.iter().collect::<Vec<_>>(), Vec::<String>::new());
+}
+
+#[test]
+fn test_records_escape_quotes_and_newlines() {
+    let dir = TempDir::new("escape");
+    let watched = dir.watched();
+    let watchcrab = Watchcrab::start(&[
+        "--path",
+        watched.to_str().unwrap(),
+        "--events",
+        "create",
+        "--args",
+        r#"printf 'say "hi"\nbye\\'"#,
+    ]);
+
+    fs::write(watched.join("new.txt"), "").unwrap();
+    let lines = watchcrab.stdout_lines(1);
+    let (status, _) = watchcrab.interrupt();
+    assert!(status.success());
+
+    let record: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(record["stdout"], "say \"hi\"\nbye\\");
+    assert_eq!(record["stderr"], "");
+}
+
+#[test]
+fn test_default_record_escapes_the_path() {
+    let dir = TempDir::new("escape-path");
+    let watched = dir.watched();
+    let watchcrab = Watchcrab::start(&["--path", watched.to_str().unwrap(), "--events", "create"]);
+
+    let path = watched.join("a \"quoted\"\\name");
+    fs::write(&path, "").unwrap();
+    let lines = watchcrab.stdout_lines(1);
+    let (status, _) = watchcrab.interrupt();
+    assert!(status.success());
+
+    let record: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(record["Path"], path.to_str().unwrap());
+}